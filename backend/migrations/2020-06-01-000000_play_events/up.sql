@@ -0,0 +1,14 @@
+START TRANSACTION;
+  -- Individual plays pulled from users' recently-played history
+  CREATE TABLE `spotify_homepage`.`play_events` (
+    `id` BIGINT NOT NULL AUTO_INCREMENT,
+    `user_id` BIGINT NOT NULL,
+    `mapped_spotify_id` INT NOT NULL,
+    `played_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (mapped_spotify_id) REFERENCES spotify_items(id) ON DELETE CASCADE
+  ) ROW_FORMAT=COMPRESSED CHARSET=ascii;
+  -- A user can't play two tracks at the same instant, so this de-duplicates plays seen across multiple fetches
+  ALTER TABLE `spotify_homepage`.`play_events` ADD UNIQUE `unique_index`(`user_id`, `played_at`);
+COMMIT;
@@ -0,0 +1,4 @@
+START TRANSACTION;
+  DROP TABLE `spotify_homepage`.`artist_stats_history`;
+  DROP TABLE `spotify_homepage`.`track_stats_history`;
+COMMIT;
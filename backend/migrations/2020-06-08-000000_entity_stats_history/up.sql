@@ -0,0 +1,25 @@
+START TRANSACTION;
+  -- Global (not user-specific) stats for artists, recorded every time an artist is seen during an update
+  CREATE TABLE `spotify_homepage`.`artist_stats_history` (
+    `id` BIGINT NOT NULL AUTO_INCREMENT,
+    `mapped_spotify_id` INT NOT NULL,
+    `update_time` DATETIME NOT NULL,
+    `followers` BIGINT UNSIGNED NOT NULL,
+    `popularity` BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY (`id`),
+    FOREIGN KEY (mapped_spotify_id) REFERENCES spotify_items(id) ON DELETE CASCADE
+  ) ROW_FORMAT=COMPRESSED CHARSET=ascii;
+  ALTER TABLE `spotify_homepage`.`artist_stats_history` ADD UNIQUE `unique_index`(`mapped_spotify_id`, `update_time`);
+
+  -- Global (not user-specific) stats for tracks, recorded every time a track is seen during an update
+  CREATE TABLE `spotify_homepage`.`track_stats_history` (
+    `id` BIGINT NOT NULL AUTO_INCREMENT,
+    `mapped_spotify_id` INT NOT NULL,
+    `update_time` DATETIME NOT NULL,
+    `popularity` BIGINT UNSIGNED NOT NULL,
+    `playcount` BIGINT UNSIGNED,
+    PRIMARY KEY (`id`),
+    FOREIGN KEY (mapped_spotify_id) REFERENCES spotify_items(id) ON DELETE CASCADE
+  ) ROW_FORMAT=COMPRESSED CHARSET=ascii;
+  ALTER TABLE `spotify_homepage`.`track_stats_history` ADD UNIQUE `unique_index`(`mapped_spotify_id`, `update_time`);
+COMMIT;
@@ -0,0 +1,6 @@
+START TRANSACTION;
+  ALTER TABLE `spotify_homepage`.`users`
+    DROP COLUMN `refresh_failure_count`,
+    DROP COLUMN `last_refresh_error`,
+    DROP COLUMN `disabled`;
+COMMIT;
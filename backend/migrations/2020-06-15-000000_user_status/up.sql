@@ -0,0 +1,7 @@
+START TRANSACTION;
+  -- Track token refresh failures so that users who have revoked the app's access stop getting updated
+  ALTER TABLE `spotify_homepage`.`users`
+    ADD COLUMN `refresh_failure_count` INT UNSIGNED NOT NULL DEFAULT 0,
+    ADD COLUMN `last_refresh_error` TEXT,
+    ADD COLUMN `disabled` BOOLEAN NOT NULL DEFAULT FALSE;
+COMMIT;
@@ -0,0 +1,3 @@
+START TRANSACTION;
+  ALTER TABLE `spotify_homepage`.`users` DROP COLUMN `access_token_expires_at`;
+COMMIT;
@@ -0,0 +1,4 @@
+START TRANSACTION;
+  -- Existing users get a `NULL` expiry, which is treated as expired so their token is refreshed on next use
+  ALTER TABLE `spotify_homepage`.`users` ADD COLUMN `access_token_expires_at` DATETIME;
+COMMIT;
@@ -0,0 +1,26 @@
+START TRANSACTION;
+  -- One row per update per user.  Rankings are only stored for timeframes that changed since the previous update, so
+  -- these bitmasks (bit `n` set => timeframe with id `n` stored) record which timeframes have rows in the snapshot
+  -- tables for the update.  Timeframes that aren't stored carry forward the last stored rankings.
+  CREATE TABLE `spotify_homepage`.`stats_updates` (
+    `id` BIGINT NOT NULL AUTO_INCREMENT,
+    `user_id` BIGINT NOT NULL,
+    `update_time` DATETIME NOT NULL,
+    `artist_timeframes` TINYINT UNSIGNED NOT NULL,
+    `track_timeframes` TINYINT UNSIGNED NOT NULL,
+    PRIMARY KEY (`id`),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+  );
+  ALTER TABLE `spotify_homepage`.`stats_updates` ADD UNIQUE `unique_index`(`user_id`, `update_time`);
+
+  -- Backfill entries for all existing updates, which stored every timeframe they had data for
+  INSERT INTO `spotify_homepage`.`stats_updates` (`user_id`, `update_time`, `artist_timeframes`, `track_timeframes`)
+    SELECT `user_id`, `update_time`, BIT_OR(1 << `timeframe`), 0
+    FROM `spotify_homepage`.`artist_rank_snapshots`
+    GROUP BY `user_id`, `update_time`;
+  INSERT INTO `spotify_homepage`.`stats_updates` (`user_id`, `update_time`, `artist_timeframes`, `track_timeframes`)
+    SELECT `user_id`, `update_time`, 0, BIT_OR(1 << `timeframe`)
+    FROM `spotify_homepage`.`track_rank_snapshots`
+    GROUP BY `user_id`, `update_time`
+    ON DUPLICATE KEY UPDATE `track_timeframes` = VALUES(`track_timeframes`);
+COMMIT;
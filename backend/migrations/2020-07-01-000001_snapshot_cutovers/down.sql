@@ -0,0 +1,3 @@
+START TRANSACTION;
+  DROP TABLE `spotify_homepage`.`snapshot_cutovers`;
+COMMIT;
@@ -0,0 +1,13 @@
+START TRANSACTION;
+  -- Records when changes to how snapshots are stored took effect so that maintenance tasks can tell which snapshots
+  -- were stored the old way.  Times are UTC, matching `update_time` in the snapshot tables.
+  CREATE TABLE `spotify_homepage`.`snapshot_cutovers` (
+    `name` VARCHAR(64) NOT NULL,
+    `cutover_time` DATETIME NOT NULL,
+    PRIMARY KEY (`name`)
+  );
+
+  -- Snapshots are stored atomically along with their `stats_updates` entry starting with this release.  Every update
+  -- stored before this may have been left half-written.
+  INSERT INTO `spotify_homepage`.`snapshot_cutovers` (`name`, `cutover_time`) VALUES ('atomic_snapshots', UTC_TIMESTAMP());
+COMMIT;
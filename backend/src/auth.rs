@@ -0,0 +1,59 @@
+//! Request guard for routes that act on behalf of a user.  Users authenticate by passing a Spotify access token for
+//! their account as a bearer token; the token is used to look up their Spotify profile which determines who they are.
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+
+use crate::error::Error;
+
+pub struct SpotifyUserAuth {
+    /// Spotify ID of the user that owns the provided access token
+    pub spotify_id: String,
+}
+
+impl SpotifyUserAuth {
+    /// Returns `true` if the authenticated user is the user with the provided Spotify ID
+    pub fn is_user(&self, user_spotify_id: &str) -> bool {
+        self.spotify_id == user_spotify_id
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SpotifyUserAuth {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let token = match request.headers().get_one("Authorization") {
+            Some(header) if header.starts_with("Bearer ") => &header["Bearer ".len()..],
+            _ => {
+                return Outcome::Failure((
+                    Status::Unauthorized,
+                    Error::Unauthorized(
+                        "A Spotify access token must be provided as a bearer token".into(),
+                    ),
+                ))
+            }
+        };
+
+        match crate::spotify_api::get_user_profile_info(token) {
+            Ok(profile) => Outcome::Success(SpotifyUserAuth {
+                spotify_id: profile.id,
+            }),
+            Err(err) => {
+                info!(
+                    "Failed to authenticate user with Spotify access token: {}",
+                    err
+                );
+                // Spotify rejecting the token means that it's invalid or expired; anything else is a problem on
+                // Spotify's end or ours
+                let status = match &err {
+                    Error::SpotifyApi {
+                        status: Some(401), ..
+                    } => Status::Unauthorized,
+                    _ => err.status(),
+                };
+                Outcome::Failure((status, err))
+            }
+        }
+    }
+}
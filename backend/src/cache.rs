@@ -1,82 +1,314 @@
-//! Functions for interacting with Redis which caches data from the Spotify API.
+//! Caches data from the Spotify API.  Values are grouped into named hashes, such as one for artists and one for tracks,
+//! and can be stored in Redis, in an in-process LRU cache, or in both with the local cache in front of Redis.  Which one
+//! is used is controlled by the `cache.backend` setting.
 
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::{Duration, Utc};
+use hashbrown::HashMap;
 use r2d2_redis::redis::Commands;
 use r2d2_redis::{r2d2, RedisConnectionManager};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-use crate::conf::CONF;
+use crate::conf::{CacheBackend, CONF};
+use crate::error::Error;
+
+/// Storage for serialized Spotify metadata.  Implementations deal in raw strings so that they can be used as trait
+/// objects; `get_hash_items` and `set_hash_items` take care of (de)serialization.
+pub trait MetadataCache: Send + Sync {
+    /// Returns the value stored for each of the keys in the hash, or `None` for keys that aren't cached
+    fn get_many(&self, hash_name: &str, keys: &[&str]) -> Result<Vec<Option<String>>, Error>;
+
+    fn set_many(&self, hash_name: &str, kv_pairs: &[(&str, String)]) -> Result<(), Error>;
+}
 
 lazy_static! {
     pub static ref REDIS_CONN_POOL: r2d2::Pool<RedisConnectionManager> = {
         let manager = RedisConnectionManager::new(CONF.redis_url.as_str())
             .expect("Failed to create Redis connection manager");
         r2d2::Pool::builder()
+            .max_size(CONF.redis_pool_size)
             .build(manager)
             .expect("Failed to build Redis connection pool")
     };
+    /// The cache used for all Spotify metadata, as configured by `cache.backend`
+    pub static ref METADATA_CACHE: Box<dyn MetadataCache> = match CONF.cache_backend {
+        CacheBackend::Redis => Box::new(RedisCache),
+        CacheBackend::Memory => Box::new(InMemoryCache::new(CONF.local_cache_capacity)),
+        CacheBackend::Tiered => Box::new(TieredCache::new(
+            InMemoryCache::new(CONF.local_cache_capacity),
+            RedisCache,
+        )),
+    };
 }
 
-fn get_conn() -> Result<diesel::r2d2::PooledConnection<RedisConnectionManager>, String> {
-    REDIS_CONN_POOL.get().map_err(|err| -> String {
+fn get_conn() -> Result<diesel::r2d2::PooledConnection<RedisConnectionManager>, Error> {
+    REDIS_CONN_POOL.get().map_err(|err| -> Error {
         error!("Error getting client from connection pool: {:?}", err);
-        "Error connecting to Spotify metadata cache".into()
+        Error::cache("Error connecting to Spotify metadata cache", err)
     })
 }
 
-pub fn set_hash_items<T: Serialize>(hash_name: &str, kv_pairs: &[(&str, T)]) -> Result<(), String> {
+/// Stores each hash as a Redis hash, shared between all instances of the backend
+pub struct RedisCache;
+
+impl MetadataCache for RedisCache {
+    fn get_many(&self, hash_name: &str, keys: &[&str]) -> Result<Vec<Option<String>>, Error> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut conn = get_conn()?;
+
+        let mut cmd = redis::cmd("HMGET");
+        let cmd = keys
+            .iter()
+            .fold(cmd.arg(hash_name), |acc, key| acc.arg(*key));
+
+        cmd.query::<Vec<Option<String>>>(&mut *conn)
+            .map_err(|err| -> Error {
+                error!("Error pulling data from Redis cache: {:?}", err);
+                Error::cache("Error pulling data from Redis cache", err)
+            })
+    }
+
+    fn set_many(&self, hash_name: &str, kv_pairs: &[(&str, String)]) -> Result<(), Error> {
+        if kv_pairs.is_empty() {
+            return Ok(());
+        }
+
+        get_conn()?
+            .hset_multiple::<&str, &str, String, ()>(hash_name, kv_pairs)
+            .map_err(|err| -> Error {
+                error!(
+                    "Error setting hash items into hash \"{}\": {:?}",
+                    hash_name, err
+                );
+                Error::cache("Error setting values into cache", err)
+            })
+    }
+}
+
+struct LruState {
+    /// Maps `(hash name, key)` to the value along with the tick at which it was last used
+    entries: HashMap<(String, String), (String, u64)>,
+    /// Maps the tick at which each entry was last used to its key, ordered from least to most recently used
+    recency: BTreeMap<u64, (String, String)>,
+    tick: u64,
+}
+
+impl LruState {
+    fn touch(&mut self, key: &(String, String)) -> Option<&String> {
+        let tick = self.tick;
+        let (val, last_used) = self.entries.get_mut(key)?;
+        let entry_key = self
+            .recency
+            .remove(&*last_used)
+            .expect("LRU cache entry missing from recency index");
+        *last_used = tick;
+        self.recency.insert(tick, entry_key);
+        self.tick += 1;
+        Some(val)
+    }
+}
+
+/// Keeps up to `capacity` entries across all hashes in process memory, evicting the least recently used ones first
+pub struct InMemoryCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+}
+
+impl InMemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        InMemoryCache {
+            capacity,
+            state: Mutex::new(LruState {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+}
+
+impl MetadataCache for InMemoryCache {
+    fn get_many(&self, hash_name: &str, keys: &[&str]) -> Result<Vec<Option<String>>, Error> {
+        let state = &mut *self.state.lock().unwrap();
+        Ok(keys
+            .iter()
+            .map(|&key| state.touch(&(hash_name.into(), key.into())).cloned())
+            .collect())
+    }
+
+    fn set_many(&self, hash_name: &str, kv_pairs: &[(&str, String)]) -> Result<(), Error> {
+        let state = &mut *self.state.lock().unwrap();
+        for (key, val) in kv_pairs {
+            let entry_key: (String, String) = (hash_name.into(), (*key).into());
+            if let Some((_, last_used)) = state.entries.remove(&entry_key) {
+                state.recency.remove(&last_used);
+            }
+
+            let tick = state.tick;
+            state.tick += 1;
+            state.entries.insert(entry_key.clone(), (val.clone(), tick));
+            state.recency.insert(tick, entry_key);
+
+            while state.entries.len() > self.capacity {
+                let (&oldest_tick, _) = match state.recency.iter().next() {
+                    Some(oldest) => oldest,
+                    None => break,
+                };
+                let evicted_key = state.recency.remove(&oldest_tick).unwrap();
+                state.entries.remove(&evicted_key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks a local cache before falling back to a shared remote one, copying items found remotely into the local cache
+pub struct TieredCache<R: MetadataCache> {
+    local: InMemoryCache,
+    remote: R,
+}
+
+impl<R: MetadataCache> TieredCache<R> {
+    pub fn new(local: InMemoryCache, remote: R) -> Self {
+        TieredCache { local, remote }
+    }
+}
+
+impl<R: MetadataCache> MetadataCache for TieredCache<R> {
+    fn get_many(&self, hash_name: &str, keys: &[&str]) -> Result<Vec<Option<String>>, Error> {
+        let mut vals = self.local.get_many(hash_name, keys)?;
+        let (missing_indices, missing_keys): (Vec<usize>, Vec<&str>) = vals
+            .iter()
+            .enumerate()
+            .filter(|(_, val)| val.is_none())
+            .map(|(i, _)| (i, keys[i]))
+            .unzip();
+        if missing_keys.is_empty() {
+            return Ok(vals);
+        }
+
+        let remote_vals = self.remote.get_many(hash_name, &missing_keys)?;
+        let mut found_remotely = Vec::new();
+        for ((i, key), val) in missing_indices
+            .into_iter()
+            .zip(missing_keys)
+            .zip(remote_vals)
+        {
+            if let Some(val) = val {
+                found_remotely.push((key, val.clone()));
+                vals[i] = Some(val);
+            }
+        }
+        self.local.set_many(hash_name, &found_remotely)?;
+
+        Ok(vals)
+    }
+
+    fn set_many(&self, hash_name: &str, kv_pairs: &[(&str, String)]) -> Result<(), Error> {
+        self.remote.set_many(hash_name, kv_pairs)?;
+        self.local.set_many(hash_name, kv_pairs)
+    }
+}
+
+pub fn set_hash_items<T: Serialize>(
+    cache: &dyn MetadataCache,
+    hash_name: &str,
+    kv_pairs: &[(&str, T)],
+) -> Result<(), Error> {
     let kv_pairs_serialized = kv_pairs
         .iter()
-        .map(|(key, val)| -> Result<(&str, String), String> {
-            let serialized: String = serde_json::to_string(val).map_err(|err| -> String {
+        .map(|(key, val)| -> Result<(&str, String), Error> {
+            let serialized: String = serde_json::to_string(val).map_err(|err| -> Error {
                 error!("Error serializing value to string: {:?}", err);
-                "Error saving items to cache".into()
+                Error::cache("Error saving items to cache", err)
             })?;
 
             Ok((key, serialized))
         })
-        .collect::<Result<Vec<_>, String>>()?;
-
-    get_conn()?
-        .hset_multiple::<&str, &str, String, ()>(hash_name, &kv_pairs_serialized)
-        .map_err(|err| -> String {
-            error!(
-                "Error setting hash items into hash \"{}\": {:?}",
-                hash_name, err
-            );
-            "Error setting values into cache".into()
-        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    cache.set_many(hash_name, &kv_pairs_serialized)
 }
 
 pub fn get_hash_items<T: for<'de> Deserialize<'de>>(
+    cache: &dyn MetadataCache,
     hash_name: &str,
     keys: &[&str],
-) -> Result<Vec<Option<T>>, String> {
-    if keys.is_empty() {
-        return Ok(Vec::new());
+) -> Result<Vec<Option<T>>, Error> {
+    cache
+        .get_many(hash_name, keys)?
+        .into_iter()
+        .map(|opt: Option<String>| match opt {
+            Some(val) => serde_json::from_str(&val).map_err(|err| -> Error {
+                error!("Error deserializing value: {:?}", err);
+                Error::cache("Error reading values from cache", err)
+            }),
+            None => Ok(None),
+        })
+        .collect::<Result<Vec<Option<T>>, Error>>()
+}
+
+/// Entity stored in the artists and tracks caches along with when it was fetched from Spotify, so that it can be
+/// refreshed once it's older than the TTL for its entity type
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CachedEntry<T> {
+    /// Unix timestamp in seconds
+    pub fetched_at: i64,
+    pub value: T,
+}
+
+impl<T> CachedEntry<T> {
+    pub fn new(value: T) -> Self {
+        CachedEntry {
+            fetched_at: Utc::now().timestamp(),
+            value,
+        }
+    }
+
+    pub fn is_stale(&self, ttl: Duration) -> bool {
+        Utc::now().timestamp() - self.fetched_at > ttl.num_seconds()
     }
+}
 
-    let mut conn = get_conn()?;
+/// Entities cached before fetch times were recorded were stored on their own.  They're read as having been fetched at
+/// the epoch so that they get refreshed.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEntry<T> {
+    Current(CachedEntry<T>),
+    Legacy(T),
+}
 
-    let mut cmd = redis::cmd("HMGET");
-    let cmd = keys
-        .iter()
-        .fold(cmd.arg(hash_name), |acc, key| acc.arg(*key));
+pub fn set_entries<T: Serialize>(
+    cache: &dyn MetadataCache,
+    hash_name: &str,
+    kv_pairs: &[(&str, CachedEntry<T>)],
+) -> Result<(), Error> {
+    set_hash_items(cache, hash_name, kv_pairs)
+}
 
-    cmd.query::<Vec<Option<String>>>(&mut *conn)
-        .map_err(|err| -> String {
-            error!("Error pulling data from Redis cache: {:?}", err);
-            "Error pulling data from Redis cache".into()
-        })?
+pub fn get_entries<T: for<'de> Deserialize<'de>>(
+    cache: &dyn MetadataCache,
+    hash_name: &str,
+    keys: &[&str],
+) -> Result<Vec<Option<CachedEntry<T>>>, Error> {
+    let entries = get_hash_items::<StoredEntry<T>>(cache, hash_name, keys)?
         .into_iter()
-        .map(|opt: Option<String>| match opt {
-            Some(val) => serde_json::from_str(&val).map_err(|err| -> String {
-                error!("Error deserializing value: {:?}", err);
-                "Error reading values from cache".into()
+        .map(|entry| match entry? {
+            StoredEntry::Current(entry) => Some(entry),
+            StoredEntry::Legacy(value) => Some(CachedEntry {
+                fetched_at: 0,
+                value,
             }),
-            None => Ok(None),
         })
-        .collect::<Result<Vec<Option<T>>, String>>()
+        .collect();
+    Ok(entries)
 }
 
 #[test]
@@ -85,15 +317,99 @@ fn cache_set_get() {
     struct Foo(String);
 
     set_hash_items(
+        &RedisCache,
         "__test",
         &[("key1", Foo("val1".into())), ("key3", Foo("val3".into()))],
     )
     .expect("Error setting hash items");
-    let vals: Vec<Option<Foo>> =
-        get_hash_items("__test", &["key1", "key2", "key3"]).expect("Error fetching hash values");
+    let vals: Vec<Option<Foo>> = get_hash_items(&RedisCache, "__test", &["key1", "key2", "key3"])
+        .expect("Error fetching hash values");
 
     assert_eq!(
         vals,
         vec![Some(Foo("val1".into())), None, Some(Foo("val3".into()))]
     );
 }
+
+#[test]
+fn in_memory_and_tiered_caches() {
+    let val = |s: &str| -> String { s.into() };
+
+    let lru = InMemoryCache::new(2);
+    lru.set_many("a", &[("key1", val("1")), ("key2", val("2"))])
+        .unwrap();
+    // The same key in a different hash is a different entry
+    assert_eq!(lru.get_many("b", &["key1"]).unwrap(), vec![None]);
+    // Reading `key1` makes `key2` the least recently used entry, so it's the one evicted
+    assert_eq!(lru.get_many("a", &["key1"]).unwrap(), vec![Some(val("1"))]);
+    lru.set_many("a", &[("key3", val("3"))]).unwrap();
+    assert_eq!(
+        lru.get_many("a", &["key1", "key2", "key3"]).unwrap(),
+        vec![Some(val("1")), None, Some(val("3"))]
+    );
+
+    let remote = InMemoryCache::new(10);
+    remote
+        .set_many("a", &[("key1", val("1")), ("key2", val("2"))])
+        .unwrap();
+    let tiered = TieredCache::new(InMemoryCache::new(10), remote);
+    tiered.set_many("a", &[("key3", val("3"))]).unwrap();
+    assert_eq!(
+        tiered.get_many("a", &["key1", "key4", "key3"]).unwrap(),
+        vec![Some(val("1")), None, Some(val("3"))]
+    );
+    // Items found in the remote cache and items written through the tiered cache are kept locally
+    assert_eq!(
+        tiered
+            .local
+            .get_many("a", &["key1", "key2", "key3"])
+            .unwrap(),
+        vec![Some(val("1")), None, Some(val("3"))]
+    );
+    assert_eq!(
+        tiered.remote.get_many("a", &["key3"]).unwrap(),
+        vec![Some(val("3"))]
+    );
+}
+
+#[test]
+fn cached_entry_staleness() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Foo {
+        name: String,
+    }
+
+    let cache = InMemoryCache::new(10);
+    set_entries(
+        &cache,
+        "__test",
+        &[(
+            "fresh",
+            CachedEntry::new(Foo {
+                name: "fresh".into(),
+            }),
+        )],
+    )
+    .unwrap();
+    // Written before entries were wrapped with their fetch time
+    set_hash_items(
+        &cache,
+        "__test",
+        &[(
+            "legacy",
+            Foo {
+                name: "legacy".into(),
+            },
+        )],
+    )
+    .unwrap();
+
+    let entries = get_entries::<Foo>(&cache, "__test", &["fresh", "legacy", "missing"]).unwrap();
+    let fresh = entries[0].as_ref().expect("Missing fresh entry");
+    assert_eq!(fresh.value.name, "fresh");
+    assert!(!fresh.is_stale(Duration::hours(1)));
+    let legacy = entries[1].as_ref().expect("Missing legacy entry");
+    assert_eq!(legacy.value.name, "legacy");
+    assert!(legacy.is_stale(Duration::days(365)));
+    assert!(entries[2].is_none());
+}
@@ -1,14 +1,42 @@
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::{http::Method, http::Status, Request, Response};
 
+use crate::conf::CONF;
+
 pub struct CorsFairing;
 
 impl Fairing for CorsFairing {
     fn on_response(&self, request: &Request, response: &mut Response) {
-        // Add CORS headers to allow all origins to all outgoing requests
+        // Allow the configured origins to make cross-origin requests.  Unless all origins are allowed, the request's
+        // origin is echoed back if it's in the list and the header is left off otherwise.
+        if CONF.cors_allowed_origins.iter().any(|origin| origin == "*") {
+            response.set_header(rocket::http::Header::new(
+                "Access-Control-Allow-Origin",
+                "*",
+            ));
+        } else {
+            let origin = request.headers().get_one("Origin").filter(|&origin| {
+                CONF.cors_allowed_origins
+                    .iter()
+                    .any(|allowed| allowed == origin)
+            });
+            if let Some(origin) = origin {
+                response.set_header(rocket::http::Header::new(
+                    "Access-Control-Allow-Origin",
+                    origin.to_string(),
+                ));
+            }
+            response.adjoin_header(rocket::http::Header::new("Vary", "Origin"));
+        }
+        // Allow users to authenticate with their Spotify access token
+        response.set_header(rocket::http::Header::new(
+            "Access-Control-Allow-Headers",
+            "Authorization",
+        ));
+        // Preflight requests need to list methods other than `GET`/`POST` explicitly, such as for account deletion
         response.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Origin",
-            "*",
+            "Access-Control-Allow-Methods",
+            "GET, POST, DELETE, OPTIONS",
         ));
 
         // Respond to all `OPTIONS` requests with a `204` (no content) status
@@ -11,16 +11,18 @@ use hashbrown::{HashMap, HashSet};
 use serde::Serialize;
 
 use crate::benchmarking::mark;
+use crate::error::Error;
 use crate::models::{
-    Artist, ArtistGenrePair, ArtistRankHistoryResItem, HasSpotifyId, NewSpotifyIdMapping,
-    SpotifyIdMapping, StatsHistoryQueryResItem, TimeFrames, Track, TrackArtistPair, User,
+    AccessTokenResponse, Artist, ArtistGenrePair, ArtistStatsHistoryEntry, HasSpotifyId,
+    NewPlayEvent, NewSpotifyIdMapping, RankHistoryResItem, SpotifyIdMapping,
+    StatsHistoryQueryResItem, StatsUpdate, TimeFrames, Track, TrackArtistPair, TrackPlay, User,
 };
 use crate::DbConn;
 
 pub fn get_user_by_spotify_id(
     conn: &DbConn,
     supplied_spotify_id: &str,
-) -> Result<Option<User>, String> {
+) -> Result<Option<User>, Error> {
     use crate::schema::users::dsl::*;
 
     diesel_not_found_to_none(
@@ -32,48 +34,380 @@ pub fn get_user_by_spotify_id(
 
 pub fn diesel_not_found_to_none<T>(
     res: Result<T, diesel::result::Error>,
-) -> Result<Option<T>, String> {
+) -> Result<Option<T>, Error> {
     match res {
         Err(diesel::result::Error::NotFound) => Ok(None),
         Err(err) => {
             error!("Error querying user from database: {:?}", err);
-            Err("Error querying database for user.".into())
+            Err(Error::database("Error querying database for user.", err))
         }
         Ok(res) => Ok(Some(res)),
     }
 }
 
+/// Runs `f` inside of a database transaction, rolling back everything it did if it returns an error.  The error
+/// returned by `f` is passed through as-is.
+pub fn with_transaction<T, F: FnOnce() -> Result<T, Error>>(
+    conn: &DbConn,
+    f: F,
+) -> Result<T, Error> {
+    let mut inner_err: Option<Error> = None;
+    let res = conn.0.transaction::<T, diesel::result::Error, _>(|| {
+        f().map_err(|err| {
+            inner_err = Some(err);
+            diesel::result::Error::RollbackTransaction
+        })
+    });
+
+    res.map_err(|err| -> Error {
+        match inner_err.take() {
+            Some(inner_err) => inner_err,
+            None => {
+                error!("Error running database transaction: {:?}", err);
+                Error::database("Error running database transaction", err)
+            }
+        }
+    })
+}
+
 #[derive(Queryable)]
 struct StatsQueryResultItem {
     timeframe: u8,
     spotify_id: String,
 }
 
-/// Returns the top artists for the last update for the given user.  Items are returned as `(timeframe_id, artist)`.
+/// The kinds of entities that we store rank snapshots for
+#[derive(Clone, Copy, Debug)]
+pub enum SnapshotEntity {
+    Artists,
+    Tracks,
+}
+
+impl SnapshotEntity {
+    /// Returns `true` if rankings for this kind of entity were stored for the given timeframe during `update`.  If
+    /// they weren't, they were unchanged since the previous update.
+    pub fn is_stored(self, update: &StatsUpdate, timeframe_id: u8) -> bool {
+        let timeframes_mask = match self {
+            SnapshotEntity::Artists => update.artist_timeframes,
+            SnapshotEntity::Tracks => update.track_timeframes,
+        };
+        timeframes_mask & (1 << timeframe_id) != 0
+    }
+}
+
+/// Returns all updates for the given user in chronological order.
+pub fn get_stats_updates(conn: &DbConn, user: &User) -> Result<Vec<StatsUpdate>, Error> {
+    use crate::schema::stats_updates::dsl::*;
+
+    stats_updates
+        .filter(user_id.eq(user.id))
+        .order_by(update_time.asc())
+        .load::<StatsUpdate>(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error querying stats updates for user: {:?}", err);
+            Error::database("Error querying stats updates for user from database", err)
+        })
+}
+
+/// Since rankings are only stored for timeframes that changed, the rankings that were current as of `at` come from
+/// the latest update at or before `at` which stored them.  Returns the time of that update for each timeframe, or
+/// `None` if nothing had been stored for the timeframe yet.
+pub fn get_effective_update_times(
+    stats_updates: &[StatsUpdate],
+    entity: SnapshotEntity,
+    at: NaiveDateTime,
+) -> [Option<NaiveDateTime>; 3] {
+    let mut update_times = [None; 3];
+    for update in stats_updates
+        .iter()
+        .filter(|update| update.update_time <= at)
+    {
+        for timeframe_id in 0..3u8 {
+            if entity.is_stored(update, timeframe_id) {
+                update_times[timeframe_id as usize] = Some(update.update_time);
+            }
+        }
+    }
+    update_times
+}
+
+/// Restricts the updates returned by history queries to a range of time and optionally a single timeframe
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HistoryFilter {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub timeframe: Option<u8>,
+}
+
+impl HistoryFilter {
+    pub fn includes(&self, update_time: NaiveDateTime) -> bool {
+        self.from.map(|from| update_time >= from).unwrap_or(true)
+            && self.to.map(|to| update_time <= to).unwrap_or(true)
+    }
+
+    /// Returns the range of update times for which stored rankings must be loaded to produce the filtered history.
+    /// Since rankings are carried forward, this starts at the last update before `from` that stored rankings for each
+    /// of the included timeframes.
+    pub fn get_query_bounds(
+        &self,
+        stats_updates: &[StatsUpdate],
+        entity: SnapshotEntity,
+    ) -> (Option<NaiveDateTime>, Option<NaiveDateTime>) {
+        let start_time = self.from.map(|from| {
+            let effective_update_times = get_effective_update_times(stats_updates, entity, from);
+            let timeframe_ids = match self.timeframe {
+                Some(timeframe_id) => vec![timeframe_id],
+                None => vec![0, 1, 2],
+            };
+
+            timeframe_ids
+                .into_iter()
+                .filter_map(|timeframe_id| effective_update_times[timeframe_id as usize])
+                .min()
+                .unwrap_or(from)
+        });
+
+        (start_time, self.to)
+    }
+}
+
+/// Restricts a boxed query over one of the rank snapshot tables to the rows needed to build the history included by a
+/// `HistoryFilter`; see `HistoryFilter::get_query_bounds`.  This is a macro since each query selects from a different
+/// set of joined tables.
+macro_rules! filter_history_query {
+    ($query:expr, $table:ident, $filter:expr, $stats_updates:expr, $entity:expr) => {{
+        use crate::schema::$table;
+
+        let mut query = $query;
+        let (start_time, end_time) = $filter.get_query_bounds($stats_updates, $entity);
+        if let Some(start_time) = start_time {
+            query = query.filter($table::update_time.ge(start_time));
+        }
+        if let Some(end_time) = end_time {
+            query = query.filter($table::update_time.le(end_time));
+        }
+        if let Some(timeframe_id) = $filter.timeframe {
+            query = query.filter($table::timeframe.eq(timeframe_id));
+        }
+        query
+    }};
+}
+
+/// Expands a set of stored rankings grouped by update into a full history with one entry per update.  Timeframes that
+/// weren't stored for an update carry forward the rankings from the last update that stored them.  Updates in which
+/// every timeframe is empty and updates not included by `filter` are omitted.
+fn carry_forward_timeframes<U: Serialize + Clone>(
+    stats_updates: &[StatsUpdate],
+    entity: SnapshotEntity,
+    filter: &HistoryFilter,
+    stored_updates: HashMap<NaiveDateTime, TimeFrames<U>>,
+) -> Vec<(NaiveDateTime, TimeFrames<U>)> {
+    carry_forward_timeframes_from(
+        stats_updates,
+        entity,
+        filter,
+        stored_updates,
+        &mut TimeFrames::default(),
+    )
+}
+
+/// Version of `carry_forward_timeframes` that starts from the rankings in `cur_rankings`, which are left holding the
+/// rankings as of the last update.  This allows the history to be expanded a few updates at a time.
+pub fn carry_forward_timeframes_from<U: Serialize + Clone>(
+    stats_updates: &[StatsUpdate],
+    entity: SnapshotEntity,
+    filter: &HistoryFilter,
+    mut stored_updates: HashMap<NaiveDateTime, TimeFrames<U>>,
+    cur_rankings: &mut TimeFrames<U>,
+) -> Vec<(NaiveDateTime, TimeFrames<U>)> {
+    let mut output = Vec::new();
+
+    for update in stats_updates {
+        let mut stored = stored_updates
+            .remove(&update.update_time)
+            .unwrap_or_default();
+        for timeframe_id in 0..3u8 {
+            if entity.is_stored(update, timeframe_id) {
+                *cur_rankings.get_by_id_mut(timeframe_id) =
+                    std::mem::take(stored.get_by_id_mut(timeframe_id));
+            }
+        }
+
+        if filter.includes(update.update_time)
+            && cur_rankings
+                .iter()
+                .any(|(_timeframe, items)| !items.is_empty())
+        {
+            output.push((
+                update.update_time,
+                TimeFrames {
+                    short: cur_rankings.short.clone(),
+                    medium: cur_rankings.medium.clone(),
+                    long: cur_rankings.long.clone(),
+                },
+            ));
+        }
+    }
+
+    output
+}
+
+/// Single-entity version of `carry_forward_timeframes`; expands stored rankings for one entity into a history of
+/// `[short, medium, long]` rankings, one entry per update included by `filter` in which the entity was ranked in at
+/// least one timeframe.
+fn carry_forward_rankings(
+    stats_updates: &[StatsUpdate],
+    entity: SnapshotEntity,
+    filter: &HistoryFilter,
+    stored_rankings: Vec<RankHistoryResItem>,
+) -> Vec<(NaiveDateTime, [Option<u16>; 3])> {
+    let mut rankings_by_update: HashMap<NaiveDateTime, [Option<u16>; 3]> = HashMap::new();
+    for item in stored_rankings {
+        rankings_by_update
+            .entry(item.update_time)
+            .or_insert([None; 3])[item.timeframe as usize] = Some(item.ranking);
+    }
+
+    let mut cur_rankings: [Option<u16>; 3] = [None; 3];
+    let mut output = Vec::new();
+    for update in stats_updates {
+        let stored = rankings_by_update
+            .get(&update.update_time)
+            .copied()
+            .unwrap_or([None; 3]);
+        for timeframe_id in 0..3u8 {
+            if entity.is_stored(update, timeframe_id) {
+                cur_rankings[timeframe_id as usize] = stored[timeframe_id as usize];
+            }
+        }
+
+        if filter.includes(update.update_time) && cur_rankings.iter().any(Option::is_some) {
+            output.push((update.update_time, cur_rankings));
+        }
+    }
+
+    output
+}
+
+/// Returns the mapped Spotify IDs of the artists in the user's current rankings for each timeframe, in ranked order.
+pub fn get_current_artist_rankings(
+    conn: &DbConn,
+    user: &User,
+    stats_updates: &[StatsUpdate],
+) -> Result<[Vec<i32>; 3], Error> {
+    use crate::schema::artist_rank_snapshots::dsl::*;
+
+    let update_times = get_effective_update_times(
+        stats_updates,
+        SnapshotEntity::Artists,
+        user.last_update_time,
+    );
+    let mut rankings: [Vec<i32>; 3] = Default::default();
+    for (timeframe_id, timeframe_update_time) in update_times.iter().enumerate() {
+        let timeframe_update_time = match timeframe_update_time {
+            Some(timeframe_update_time) => *timeframe_update_time,
+            None => continue,
+        };
+
+        rankings[timeframe_id] = artist_rank_snapshots
+            .filter(user_id.eq(user.id))
+            .filter(timeframe.eq(timeframe_id as u8))
+            .filter(update_time.eq(timeframe_update_time))
+            .order_by(ranking.asc())
+            .select(mapped_spotify_id)
+            .load::<i32>(&conn.0)
+            .map_err(|err| -> Error {
+                error!("Error querying current artist rankings: {:?}", err);
+                Error::database("Error querying current artist rankings from database", err)
+            })?;
+    }
+
+    Ok(rankings)
+}
+
+/// Returns the mapped Spotify IDs of the tracks in the user's current rankings for each timeframe, in ranked order.
+pub fn get_current_track_rankings(
+    conn: &DbConn,
+    user: &User,
+    stats_updates: &[StatsUpdate],
+) -> Result<[Vec<i32>; 3], Error> {
+    use crate::schema::track_rank_snapshots::dsl::*;
+
+    let update_times =
+        get_effective_update_times(stats_updates, SnapshotEntity::Tracks, user.last_update_time);
+    let mut rankings: [Vec<i32>; 3] = Default::default();
+    for (timeframe_id, timeframe_update_time) in update_times.iter().enumerate() {
+        let timeframe_update_time = match timeframe_update_time {
+            Some(timeframe_update_time) => *timeframe_update_time,
+            None => continue,
+        };
+
+        rankings[timeframe_id] = track_rank_snapshots
+            .filter(user_id.eq(user.id))
+            .filter(timeframe.eq(timeframe_id as u8))
+            .filter(update_time.eq(timeframe_update_time))
+            .order_by(ranking.asc())
+            .select(mapped_spotify_id)
+            .load::<i32>(&conn.0)
+            .map_err(|err| -> Error {
+                error!("Error querying current track rankings: {:?}", err);
+                Error::database("Error querying current track rankings from database", err)
+            })?;
+    }
+
+    Ok(rankings)
+}
+
+/// Returns the time of the update closest to `at` out of the provided updates, or `None` if there are no updates.
+pub fn get_closest_update_time(
+    stats_updates: &[StatsUpdate],
+    at: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    stats_updates
+        .iter()
+        .map(|update| update.update_time)
+        .min_by_key(|&update_time| (update_time - at).num_seconds().abs())
+}
+
+/// Returns the top artists for the given user as of the update at `at` (normally the user's last update time).  Items
+/// are returned as `(timeframe_id, artist)`.
 pub fn get_artist_stats(
     user: &User,
-    conn: DbConn,
+    conn: &DbConn,
     spotify_access_token: &str,
-) -> Result<Option<Vec<(u8, Artist)>>, String> {
+    stats_updates: &[StatsUpdate],
+    at: NaiveDateTime,
+) -> Result<Option<Vec<(u8, Artist)>>, Error> {
     use crate::schema::artist_rank_snapshots::{self, dsl::*};
     use crate::schema::spotify_items::{self, dsl::*};
 
-    let artists_stats_opt = diesel_not_found_to_none(
-        artist_rank_snapshots
-            .filter(user_id.eq(user.id))
-            .filter(update_time.eq(user.last_update_time))
-            .order_by(update_time)
-            .inner_join(spotify_items)
-            .select((artist_rank_snapshots::timeframe, spotify_items::spotify_id))
-            .load::<StatsQueryResultItem>(&conn.0),
-    )?;
+    let update_times = get_effective_update_times(stats_updates, SnapshotEntity::Artists, at);
+
+    // Each timeframe's rankings may come from a different update
+    let mut artist_stats: Vec<StatsQueryResultItem> = Vec::new();
+    for (timeframe_id, timeframe_update_time) in update_times.iter().enumerate() {
+        let timeframe_update_time = match timeframe_update_time {
+            Some(timeframe_update_time) => *timeframe_update_time,
+            None => continue,
+        };
+
+        let artists_stats_opt = diesel_not_found_to_none(
+            artist_rank_snapshots
+                .filter(user_id.eq(user.id))
+                .filter(artist_rank_snapshots::timeframe.eq(timeframe_id as u8))
+                .filter(update_time.eq(timeframe_update_time))
+                .order_by(ranking)
+                .inner_join(spotify_items)
+                .select((artist_rank_snapshots::timeframe, spotify_items::spotify_id))
+                .load::<StatsQueryResultItem>(&conn.0),
+        )?;
+        match artists_stats_opt {
+            None => return Ok(None),
+            Some(res) => artist_stats.extend(res),
+        }
+    }
     mark("Got artist stats from database");
 
-    let artist_stats = match artists_stats_opt {
-        None => return Ok(None),
-        Some(res) => res,
-    };
-
     let artist_spotify_ids: Vec<&str> = artist_stats
         .iter()
         .map(|entry| entry.spotify_id.as_str())
@@ -91,25 +425,60 @@ pub fn get_artist_stats(
     Ok(Some(fetched_artists))
 }
 
-pub fn get_artist_rank_history_single_artist(
+/// Returns the rankings of a single artist or track in each timeframe for every update in which it was ranked, with
+/// rankings carried forward through updates that didn't store them.
+pub fn get_rank_history_single_entity(
     user: &User,
     conn: DbConn,
-    artist_spotify_id: &str,
-) -> Result<Option<Vec<(NaiveDateTime, [Option<u16>; 3])>>, String> {
-    use crate::schema::artist_rank_snapshots::dsl::*;
+    entity: SnapshotEntity,
+    entity_spotify_id: &str,
+    filter: &HistoryFilter,
+) -> Result<Option<Vec<(NaiveDateTime, [Option<u16>; 3])>>, Error> {
     use crate::schema::spotify_items::dsl::*;
 
-    let query = artist_rank_snapshots
-        .filter(user_id.eq(user.id))
-        .inner_join(spotify_items)
-        .filter(spotify_id.eq(artist_spotify_id))
-        .order_by(update_time.asc())
-        .select((update_time, ranking, timeframe));
-    debug!(
-        "{:?}",
-        diesel::debug_query::<diesel::mysql::Mysql, _>(&query)
-    );
-    let res = match diesel_not_found_to_none(query.load::<ArtistRankHistoryResItem>(&conn.0))? {
+    let stats_updates = get_stats_updates(&conn, user)?;
+
+    let res = match entity {
+        SnapshotEntity::Artists => {
+            use crate::schema::artist_rank_snapshots::dsl::*;
+
+            let query = artist_rank_snapshots
+                .filter(user_id.eq(user.id))
+                .inner_join(spotify_items)
+                .filter(spotify_id.eq(entity_spotify_id))
+                .order_by(update_time.asc())
+                .select((update_time, ranking, timeframe))
+                .into_boxed();
+            let query = filter_history_query!(
+                query,
+                artist_rank_snapshots,
+                filter,
+                &stats_updates,
+                SnapshotEntity::Artists
+            );
+            diesel_not_found_to_none(query.load::<RankHistoryResItem>(&conn.0))?
+        }
+        SnapshotEntity::Tracks => {
+            use crate::schema::track_rank_snapshots::dsl::*;
+
+            let query = track_rank_snapshots
+                .filter(user_id.eq(user.id))
+                .inner_join(spotify_items)
+                .filter(spotify_id.eq(entity_spotify_id))
+                .order_by(update_time.asc())
+                .select((update_time, ranking, timeframe))
+                .into_boxed();
+            let query = filter_history_query!(
+                query,
+                track_rank_snapshots,
+                filter,
+                &stats_updates,
+                SnapshotEntity::Tracks
+            );
+            diesel_not_found_to_none(query.load::<RankHistoryResItem>(&conn.0))?
+        }
+    };
+    let res = match res {
         Some(res) => res,
         None => return Ok(None),
     };
@@ -117,23 +486,130 @@ pub fn get_artist_rank_history_single_artist(
         return Ok(None);
     }
 
-    let mut output: Vec<(NaiveDateTime, [Option<u16>; 3])> = Vec::new();
+    Ok(Some(carry_forward_rankings(
+        &stats_updates,
+        entity,
+        filter,
+        res,
+    )))
+}
 
-    let mut cur_update: (NaiveDateTime, [Option<u16>; 3]) =
-        (res.first().unwrap().update_time.clone(), [None; 3]);
-    for update in res {
-        if update.update_time != cur_update.0 {
-            output.push(std::mem::replace(
-                &mut cur_update,
-                (update.update_time.clone(), [None; 3]),
-            ));
+/// Returns the Spotify IDs of all of the artists of a track along with the user's current ranking of each of them for
+/// the short, medium, and long timeframes.  Returns `None` if we've never seen the track.
+pub fn get_track_artists_rankings(
+    user: &User,
+    conn: DbConn,
+    track_spotify_id: &str,
+) -> Result<Option<Vec<(String, [Option<u16>; 3])>>, Error> {
+    use crate::schema::{artist_rank_snapshots, spotify_items, tracks_artists};
+
+    let track_inner_id: i32 = match diesel_not_found_to_none(
+        spotify_items::table
+            .filter(spotify_items::spotify_id.eq(track_spotify_id))
+            .select(spotify_items::id)
+            .first(&conn.0),
+    )? {
+        Some(track_inner_id) => track_inner_id,
+        None => return Ok(None),
+    };
+
+    let track_artists: Vec<(i32, String)> = tracks_artists::table
+        .filter(tracks_artists::track_id.eq(track_inner_id))
+        .inner_join(spotify_items::table.on(tracks_artists::artist_id.eq(spotify_items::id)))
+        .select((spotify_items::id, spotify_items::spotify_id))
+        .load(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error querying artists for track: {:?}", err);
+            Error::database("Error querying artists for track from database", err)
+        })?;
+    let artist_inner_ids: Vec<i32> = track_artists
+        .iter()
+        .map(|(artist_inner_id, _)| *artist_inner_id)
+        .collect();
+
+    let stats_updates = get_stats_updates(&conn, user)?;
+    let update_times = get_effective_update_times(
+        &stats_updates,
+        SnapshotEntity::Artists,
+        user.last_update_time,
+    );
+    let mut rankings_by_artist_inner_id: HashMap<i32, [Option<u16>; 3]> = HashMap::new();
+    for (timeframe_id, timeframe_update_time) in update_times.iter().enumerate() {
+        let timeframe_update_time = match timeframe_update_time {
+            Some(timeframe_update_time) => *timeframe_update_time,
+            None => continue,
+        };
+
+        let rankings: Vec<(i32, u16)> = artist_rank_snapshots::table
+            .filter(artist_rank_snapshots::user_id.eq(user.id))
+            .filter(artist_rank_snapshots::timeframe.eq(timeframe_id as u8))
+            .filter(artist_rank_snapshots::update_time.eq(timeframe_update_time))
+            .filter(artist_rank_snapshots::mapped_spotify_id.eq_any(&artist_inner_ids))
+            .select((
+                artist_rank_snapshots::mapped_spotify_id,
+                artist_rank_snapshots::ranking,
+            ))
+            .load(&conn.0)
+            .map_err(|err| -> Error {
+                error!(
+                    "Error querying current rankings of track artists: {:?}",
+                    err
+                );
+                Error::database(
+                    "Error querying current rankings of track artists from database",
+                    err,
+                )
+            })?;
+        for (artist_inner_id, artist_ranking) in rankings {
+            rankings_by_artist_inner_id
+                .entry(artist_inner_id)
+                .or_insert([None; 3])[timeframe_id] = Some(artist_ranking);
         }
+    }
+
+    Ok(Some(
+        track_artists
+            .into_iter()
+            .map(|(artist_inner_id, artist_spotify_id)| {
+                let rankings = rankings_by_artist_inner_id
+                    .get(&artist_inner_id)
+                    .copied()
+                    .unwrap_or([None; 3]);
+                (artist_spotify_id, rankings)
+            })
+            .collect(),
+    ))
+}
+
+/// Returns the global follower and popularity history for an artist, collected from all users' updates in which
+/// that artist appeared.
+pub fn get_artist_global_stats_history(
+    conn: DbConn,
+    artist_spotify_id: &str,
+    filter: &HistoryFilter,
+) -> Result<Vec<ArtistStatsHistoryEntry>, Error> {
+    use crate::schema::artist_stats_history::dsl::*;
+    use crate::schema::spotify_items::dsl::*;
 
-        cur_update.1[update.timeframe as usize] = Some(update.ranking);
+    let mut query = artist_stats_history
+        .inner_join(spotify_items)
+        .filter(spotify_id.eq(artist_spotify_id))
+        .order_by(update_time.asc())
+        .select((update_time, followers, popularity))
+        .into_boxed();
+    if let Some(from) = filter.from {
+        query = query.filter(update_time.ge(from));
+    }
+    if let Some(to) = filter.to {
+        query = query.filter(update_time.le(to));
     }
-    output.push(cur_update);
 
-    Ok(Some(output))
+    query
+        .load::<ArtistStatsHistoryEntry>(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error querying artist stats history: {:?}", err);
+            Error::database("Error querying artist stats history from database", err)
+        })
 }
 
 pub fn group_updates_by_timestamp<T>(
@@ -157,21 +633,25 @@ pub fn group_updates_by_timestamp<T>(
 /// mapping from spotify id to entity along with the sorted + grouped metrics.
 ///
 /// The data returned by this function is useful for generating graphs on the frontend showing how the rankings of
-/// different entities changes over time.
+/// different entities changes over time.  Since rankings are only stored for timeframes that changed, the stored
+/// updates are expanded using `stats_updates` so that every update is present in the output.
 fn get_entity_stats_history<
     T: HasSpotifyId + Debug,
     Q: RunQueryDsl<MysqlConnection> + QueryFragment<Mysql> + Query + QueryId,
-    U: Serialize+Debug,
+    U: Serialize + Debug + Clone,
 >(
     conn: DbConn,
+    stats_updates: &[StatsUpdate],
+    entity: SnapshotEntity,
+    filter: &HistoryFilter,
     query: Q,
     spotify_access_token: &str,
     fetch_entities: fn(
         spotify_access_token: &str,
         entity_spotify_ids: &[&str],
-    ) -> Result<Vec<T>, String>,
+    ) -> Result<Vec<T>, Error>,
     get_update_item: fn(&StatsHistoryQueryResItem) -> U,
-) -> Result<Option<(HashMap<String, T>, Vec<(NaiveDateTime, TimeFrames<U>)>)>, String>
+) -> Result<Option<(HashMap<String, T>, Vec<(NaiveDateTime, TimeFrames<U>)>)>, Error>
 where
     (String, NaiveDateTime, u16, u8): Queryable<<Q as Query>::SqlType, Mysql>,
     Mysql: HasSqlType<<Q as Query>::SqlType>,
@@ -205,7 +685,7 @@ where
         &entity_stats,
     );
 
-    let mut updates: Vec<(NaiveDateTime, TimeFrames<U>)> = entity_stats_by_update_timestamp
+    let stored_updates: HashMap<NaiveDateTime, TimeFrames<U>> = entity_stats_by_update_timestamp
         .into_iter()
         .map(|(update_timestamp, mut entries_for_update)| {
             entries_for_update
@@ -225,7 +705,7 @@ where
             (update_timestamp, stats_for_update)
         })
         .collect();
-    updates.sort_unstable_by_key(|update| update.0);
+    let updates = carry_forward_timeframes(stats_updates, entity, filter, stored_updates);
 
     return Ok(Some((entities_by_id, updates)));
 }
@@ -234,30 +714,36 @@ pub fn get_artist_stats_history(
     user: &User,
     conn: DbConn,
     spotify_access_token: &str,
-    restrict_to_timeframe_id: Option<u8>,
+    filter: &HistoryFilter,
 ) -> Result<
     Option<(
         HashMap<String, Artist>,
         Vec<(NaiveDateTime, TimeFrames<String>)>,
     )>,
-    String,
+    Error,
 > {
     use crate::schema::artist_rank_snapshots::dsl::*;
     use crate::schema::spotify_items::dsl::*;
 
-    let mut query = artist_rank_snapshots
+    let query = artist_rank_snapshots
         .filter(user_id.eq(user.id))
+        .inner_join(spotify_items)
+        .select((spotify_id, update_time, ranking, timeframe))
         .into_boxed();
-    if let Some(timeframe_id) = restrict_to_timeframe_id {
-        query = query.filter(timeframe.eq(timeframe_id))
-    }
-    let query =
-        query
-            .inner_join(spotify_items)
-            .select((spotify_id, update_time, ranking, timeframe));
+    let stats_updates = get_stats_updates(&conn, user)?;
+    let query = filter_history_query!(
+        query,
+        artist_rank_snapshots,
+        filter,
+        &stats_updates,
+        SnapshotEntity::Artists
+    );
 
     get_entity_stats_history(
         conn,
+        &stats_updates,
+        SnapshotEntity::Artists,
+        filter,
         query,
         spotify_access_token,
         crate::spotify_api::fetch_artists,
@@ -265,7 +751,7 @@ pub fn get_artist_stats_history(
     )
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct ArtistRanking {
     pub artist_spotify_id: String,
     pub ranking: u16,
@@ -276,12 +762,13 @@ pub fn get_genre_stats_history(
     conn: DbConn,
     spotify_access_token: &str,
     target_genre: &str,
+    filter: &HistoryFilter,
 ) -> Result<
     Option<(
         HashMap<String, Artist>,
         Vec<(NaiveDateTime, TimeFrames<ArtistRanking>)>,
     )>,
-    String,
+    Error,
 > {
     use crate::schema::artist_rank_snapshots::{self, dsl::*};
     use crate::schema::artists_genres::{self, dsl::*};
@@ -295,10 +782,22 @@ pub fn get_genre_stats_history(
                 artist_rank_snapshots::dsl::mapped_spotify_id.eq(artists_genres::dsl::artist_id),
             ))
             .inner_join(spotify_items)
-            .select((spotify_id, update_time, ranking, timeframe));
+            .select((spotify_id, update_time, ranking, timeframe))
+            .into_boxed();
+    let stats_updates = get_stats_updates(&conn, user)?;
+    let query = filter_history_query!(
+        query,
+        artist_rank_snapshots,
+        filter,
+        &stats_updates,
+        SnapshotEntity::Artists
+    );
 
     get_entity_stats_history(
         conn,
+        &stats_updates,
+        SnapshotEntity::Artists,
+        filter,
         query,
         spotify_access_token,
         crate::spotify_api::fetch_artists,
@@ -309,48 +808,103 @@ pub fn get_genre_stats_history(
     )
 }
 
-/// Returns a list of track data items for each of the top tracks for the user's most recent update.  The first item
-/// of the tuple is the timeframe ID: short, medium, long.
+/// Returns a list of track data items for each of the top tracks for the user as of the update at `at` (normally the
+/// user's last update time).  The first item of the tuple is the timeframe ID: short, medium, long.
 pub fn get_track_stats(
     user: &User,
-    conn: DbConn,
+    conn: &DbConn,
     spotify_access_token: &str,
-) -> Result<Option<Vec<(u8, Track)>>, String> {
+    stats_updates: &[StatsUpdate],
+    at: NaiveDateTime,
+) -> Result<Option<Vec<(u8, Track)>>, Error> {
     use crate::schema::spotify_items::dsl::*;
     use crate::schema::track_rank_snapshots::dsl::*;
 
-    let track_stats_opt = diesel_not_found_to_none(
-        track_rank_snapshots
-            .filter(user_id.eq(user.id))
-            // Only include tracks from the most recent update
-            .filter(update_time.eq(user.last_update_time))
-            .order_by(update_time)
-            .inner_join(spotify_items)
-            .select((timeframe, spotify_id))
-            .load::<StatsQueryResultItem>(&conn.0),
-    )?;
+    let update_times = get_effective_update_times(stats_updates, SnapshotEntity::Tracks, at);
 
-    let track_stats = match track_stats_opt {
-        None => return Ok(None),
-        Some(res) => res,
-    };
+    // Each timeframe's rankings may come from a different update
+    let mut track_stats: Vec<StatsQueryResultItem> = Vec::new();
+    for (timeframe_id, timeframe_update_time) in update_times.iter().enumerate() {
+        let timeframe_update_time = match timeframe_update_time {
+            Some(timeframe_update_time) => *timeframe_update_time,
+            None => continue,
+        };
+
+        let track_stats_opt = diesel_not_found_to_none(
+            track_rank_snapshots
+                .filter(user_id.eq(user.id))
+                .filter(timeframe.eq(timeframe_id as u8))
+                .filter(update_time.eq(timeframe_update_time))
+                .order_by(ranking)
+                .inner_join(spotify_items)
+                .select((timeframe, spotify_id))
+                .load::<StatsQueryResultItem>(&conn.0),
+        )?;
+        match track_stats_opt {
+            None => return Ok(None),
+            Some(res) => track_stats.extend(res),
+        }
+    }
 
     let track_spotify_ids: Vec<&str> = track_stats
         .iter()
         .map(|entry| entry.spotify_id.as_str())
         .collect();
     let fetched_tracks =
-        crate::spotify_api::fetch_tracks(spotify_access_token, &track_spotify_ids)?
+        crate::spotify_api::fetch_optional_tracks(spotify_access_token, &track_spotify_ids)?
             .into_iter()
             .enumerate()
-            .map(|(i, track)| {
+            .filter_map(|(i, track)| {
                 let timeframe_id = track_stats[i].timeframe;
-                (timeframe_id, track)
+                track.map(|track| (timeframe_id, track))
             })
             .collect::<Vec<_>>();
     Ok(Some(fetched_tracks))
 }
 
+/// Retrieves the top tracks for all timeframes for each update for a given user, for all tracks rather than only those
+/// of a single artist as with `get_track_stats_history`.
+pub fn get_all_track_stats_history(
+    user: &User,
+    conn: DbConn,
+    spotify_access_token: &str,
+    filter: &HistoryFilter,
+) -> Result<
+    Option<(
+        HashMap<String, Track>,
+        Vec<(NaiveDateTime, TimeFrames<String>)>,
+    )>,
+    Error,
+> {
+    use crate::schema::spotify_items::dsl::*;
+    use crate::schema::track_rank_snapshots::dsl::*;
+
+    let query = track_rank_snapshots
+        .filter(user_id.eq(user.id))
+        .inner_join(spotify_items)
+        .select((spotify_id, update_time, ranking, timeframe))
+        .into_boxed();
+    let stats_updates = get_stats_updates(&conn, user)?;
+    let query = filter_history_query!(
+        query,
+        track_rank_snapshots,
+        filter,
+        &stats_updates,
+        SnapshotEntity::Tracks
+    );
+
+    get_entity_stats_history(
+        conn,
+        &stats_updates,
+        SnapshotEntity::Tracks,
+        filter,
+        query,
+        spotify_access_token,
+        crate::spotify_api::fetch_tracks,
+        |update: &StatsHistoryQueryResItem| update.spotify_id.clone(),
+    )
+}
+
 /// Retrieves the top tracks for all timeframes for each update for a given user.  Rather than duplicating track metadata,
 /// each timeframe simply stores the track ID and a `HashMap` is returned which serves as a local lookup tool for the track metadata.
 pub fn get_track_stats_history(
@@ -358,12 +912,13 @@ pub fn get_track_stats_history(
     conn: DbConn,
     spotify_access_token: &str,
     parent_artist_id: &str,
+    filter: &HistoryFilter,
 ) -> Result<
     Option<(
         HashMap<String, Track>,
         Vec<(NaiveDateTime, TimeFrames<String>)>,
     )>,
-    String,
+    Error,
 > {
     use crate::schema::spotify_items::{self, dsl::*};
     use crate::schema::track_rank_snapshots::{self, dsl::*};
@@ -373,12 +928,12 @@ pub fn get_track_stats_history(
         .filter(spotify_items::spotify_id.eq(parent_artist_id))
         .select(spotify_items::id)
         .first(&conn.0)
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!(
                 "Error querying inner id of artist with spotify id {:?}: {:?}",
                 parent_artist_id, err
             );
-            "Error looking up parent artist".into()
+            Error::database("Error looking up parent artist", err)
         })?;
 
     let query = tracks_artists
@@ -390,10 +945,22 @@ pub fn get_track_stats_history(
         .filter(user_id.eq(user.id))
         .inner_join(spotify_items.on(tracks_artists::track_id.eq(spotify_items::id)))
         .order_by(update_time)
-        .select((spotify_id, update_time, ranking, timeframe));
+        .select((spotify_id, update_time, ranking, timeframe))
+        .into_boxed();
+    let stats_updates = get_stats_updates(&conn, user)?;
+    let query = filter_history_query!(
+        query,
+        track_rank_snapshots,
+        filter,
+        &stats_updates,
+        SnapshotEntity::Tracks
+    );
 
     get_entity_stats_history(
         conn,
+        &stats_updates,
+        SnapshotEntity::Tracks,
+        filter,
         query,
         spotify_access_token,
         crate::spotify_api::fetch_tracks,
@@ -406,7 +973,7 @@ pub fn get_track_stats_history(
 pub fn retrieve_mapped_spotify_ids<'a, T: Iterator<Item = &'a String> + Clone>(
     conn: &DbConn,
     spotify_ids: T,
-) -> Result<HashMap<String, i32>, String> {
+) -> Result<HashMap<String, i32>, Error> {
     use crate::schema::spotify_items::dsl::*;
 
     let spotify_id_items: Vec<NewSpotifyIdMapping> = spotify_ids
@@ -421,18 +988,18 @@ pub fn retrieve_mapped_spotify_ids<'a, T: Iterator<Item = &'a String> + Clone>(
     diesel::insert_or_ignore_into(spotify_items)
         .values(spotify_id_items)
         .execute(&conn.0)
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!("Error inserting spotify ids into mapping table: {:?}", err);
-            "Error inserting spotify ids into mapping table".into()
+            Error::database("Error inserting spotify ids into mapping table", err)
         })?;
 
     // Retrieve the mapped spotify ids, including any inserted ones
     let mapped_ids: Vec<SpotifyIdMapping> = spotify_items
         .filter(spotify_id.eq_any(spotify_ids))
         .load(&conn.0)
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!("Error retrieving mapped spotify ids: {:?}", err);
-            "Error retrieving mapped spotify ids".into()
+            Error::database("Error retrieving mapped spotify ids", err)
         })?;
 
     // Match up the orderings to that the mapped ids are in the same ordering as the provided ids
@@ -449,7 +1016,7 @@ pub fn retrieve_mapped_spotify_ids<'a, T: Iterator<Item = &'a String> + Clone>(
 pub fn populate_tracks_artists_table(
     conn: &DbConn,
     spotify_access_token: &str,
-) -> Result<(), String> {
+) -> Result<(), Error> {
     use crate::schema::spotify_items::dsl::*;
     use crate::schema::track_rank_snapshots::{self, dsl::*};
     use crate::schema::tracks_artists::dsl::*;
@@ -466,12 +1033,15 @@ pub fn populate_tracks_artists_table(
         .select((track_rank_snapshots::mapped_spotify_id, spotify_id))
         .distinct()
         .load::<Ids>(&conn.0)
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!(
                 "Unable to query distinct track spotify IDs from database: {:?}",
                 err
             );
-            "Unable to query distinct track spotify IDs from database".into()
+            Error::database(
+                "Unable to query distinct track spotify IDs from database",
+                err,
+            )
         })?;
     let all_track_spotify_ids_refs = all_track_spotify_ids
         .iter()
@@ -513,12 +1083,12 @@ pub fn populate_tracks_artists_table(
     diesel::insert_or_ignore_into(tracks_artists)
         .values(&pairs)
         .execute(&conn.0)
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!(
                 "Error inserting artist/track pairs into mapping table: {:?}",
                 err
             );
-            "Error inserting artist/track pairs into mapping table".into()
+            Error::database("Error inserting artist/track pairs into mapping table", err)
         })
         .map(|_| ())
 }
@@ -526,7 +1096,7 @@ pub fn populate_tracks_artists_table(
 pub fn populate_artists_genres_table(
     conn: &DbConn,
     spotify_access_token: &str,
-) -> Result<(), String> {
+) -> Result<(), Error> {
     use crate::schema::artist_rank_snapshots::{self, dsl::*};
     use crate::schema::artists_genres::dsl::*;
     use crate::schema::spotify_items::dsl::*;
@@ -543,9 +1113,9 @@ pub fn populate_artists_genres_table(
         .select((artist_rank_snapshots::mapped_spotify_id, spotify_id))
         .distinct()
         .load::<Ids>(&conn.0)
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!("Error fetching all artist ids from database: {:?}", err);
-            "Error fetching all artist ids from database".into()
+            Error::database("Error fetching all artist ids from database", err)
         })?;
 
     let all_artist_spotify_ids = all_artist_ids
@@ -593,12 +1163,15 @@ pub fn populate_artists_genres_table(
                 .values(&pairs)
                 .execute(&conn.0)
         })
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!(
                 "Error clearing + refreshing artists/genres mapping table: {:?}",
                 err
             );
-            "Error clearing + refreshing artists/genres mapping table".into()
+            Error::database(
+                "Error clearing + refreshing artists/genres mapping table",
+                err,
+            )
         })
         .map(|_| ())
 }
@@ -609,14 +1182,907 @@ pub fn update_user_last_updated(
     user: &User,
     conn: &DbConn,
     update_time: NaiveDateTime,
-) -> Result<usize, String> {
+) -> Result<usize, Error> {
     use crate::schema::users::dsl::*;
 
     diesel::update(users.filter(id.eq(user.id)))
         .set(last_update_time.eq(update_time))
         .execute(&conn.0)
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!("Error updating user's last update time: {:?}", err);
-            "Error updating user's last update time.".into()
+            Error::database("Error updating user's last update time.", err)
+        })
+}
+
+/// Returns all enabled users whose last update happened before `cutoff`, ordered from least to most recently updated.
+pub fn get_users_due_for_update(conn: &DbConn, cutoff: NaiveDateTime) -> Result<Vec<User>, Error> {
+    use crate::schema::users::dsl::*;
+
+    users
+        .filter(disabled.eq(false))
+        .filter(last_update_time.lt(cutoff))
+        .order_by(last_update_time.asc())
+        .load::<User>(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error querying users due for update: {:?}", err);
+            Error::database("Error querying users due for update from database", err)
         })
 }
+
+/// Returns the time of the most recent play event stored for the given user, if any.
+pub fn get_latest_play_event_time(
+    conn: &DbConn,
+    user: &User,
+) -> Result<Option<NaiveDateTime>, Error> {
+    use crate::schema::play_events::dsl::*;
+
+    play_events
+        .filter(user_id.eq(user.id))
+        .select(diesel::dsl::max(played_at))
+        .first::<Option<NaiveDateTime>>(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error querying latest play event time: {:?}", err);
+            Error::database("Error querying latest play event time from database", err)
+        })
+}
+
+/// Returns the Spotify track ID and play time of every play event stored for the user between `start` and `end`
+/// (inclusive).
+pub fn get_play_events_between(
+    conn: &DbConn,
+    user: &User,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<Vec<(String, NaiveDateTime)>, Error> {
+    use crate::schema::{play_events, spotify_items};
+
+    play_events::table
+        .inner_join(spotify_items::table)
+        .filter(play_events::user_id.eq(user.id))
+        .filter(play_events::played_at.between(start, end))
+        .select((spotify_items::spotify_id, play_events::played_at))
+        .load::<(String, NaiveDateTime)>(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error querying play events: {:?}", err);
+            Error::database("Error querying play events from database", err)
+        })
+}
+
+/// Maximum number of play events to insert in a single statement
+const PLAY_EVENT_INSERT_CHUNK_SIZE: usize = 5000;
+
+/// Stores the provided plays as play events for the user, creating `spotify_items` entries for their tracks and artists
+/// as well as track/artist mappings as needed.  Plays at exactly the same time as a play that's already been stored
+/// are ignored; callers with imprecise play times need to de-duplicate them beforehand.  Returns the number of new
+/// play events that were stored.
+pub fn store_play_events(conn: &DbConn, user: &User, plays: &[TrackPlay]) -> Result<usize, Error> {
+    if plays.is_empty() {
+        return Ok(0);
+    }
+
+    // The same tracks are usually played many times, so de-duplicate everything before hitting the database
+    let track_spotify_ids: HashSet<&String> =
+        plays.iter().map(|play| &play.track_spotify_id).collect();
+    let mapped_track_spotify_ids =
+        retrieve_mapped_spotify_ids(conn, track_spotify_ids.iter().copied())?;
+    let artist_spotify_ids: HashSet<&String> = plays
+        .iter()
+        .flat_map(|play| play.artist_spotify_ids.iter())
+        .collect();
+    let mapped_artist_spotify_ids =
+        retrieve_mapped_spotify_ids(conn, artist_spotify_ids.iter().copied())?;
+
+    let track_artist_pairs: Vec<TrackArtistPair> = plays
+        .iter()
+        .flat_map(|play| {
+            let track_internal_id = mapped_track_spotify_ids[&play.track_spotify_id];
+            let mapped_artist_spotify_ids = &mapped_artist_spotify_ids;
+
+            play.artist_spotify_ids
+                .iter()
+                .map(move |artist_spotify_id| {
+                    (
+                        track_internal_id,
+                        mapped_artist_spotify_ids[artist_spotify_id],
+                    )
+                })
+        })
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(|(track_id, artist_id)| TrackArtistPair {
+            track_id,
+            artist_id,
+        })
+        .collect();
+    diesel::insert_or_ignore_into(crate::schema::tracks_artists::table)
+        .values(&track_artist_pairs)
+        .execute(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error inserting track/artist mappings: {:?}", err);
+            Error::database("Error inserting track/artist metadata into database", err)
+        })?;
+
+    let play_events: Vec<NewPlayEvent> = plays
+        .iter()
+        .map(|play| NewPlayEvent {
+            user_id: user.id,
+            mapped_spotify_id: mapped_track_spotify_ids[&play.track_spotify_id],
+            played_at: play.played_at,
+        })
+        .collect();
+    let mut inserted_count = 0;
+    for chunk in play_events.chunks(PLAY_EVENT_INSERT_CHUNK_SIZE) {
+        inserted_count += diesel::insert_or_ignore_into(crate::schema::play_events::table)
+            .values(chunk)
+            .execute(&conn.0)
+            .map_err(|err| -> Error {
+                error!("Error inserting play events: {:?}", err);
+                Error::database("Error inserting play events into database", err)
+            })?;
+    }
+
+    Ok(inserted_count)
+}
+
+/// OAuth error code returned by the Spotify accounts service when a refresh token has been revoked or is otherwise
+/// no longer valid
+const INVALID_GRANT_ERROR: &str = "invalid_grant";
+
+/// Returns `true` if Spotify rejected the user's refresh token outright, which is what happens once they revoke
+/// access.  Anything else (network errors, 5xx responses, exhausted rate limit retries) may well succeed next time.
+fn is_refresh_token_rejected(err: &Error) -> bool {
+    match err {
+        Error::SpotifyApi {
+            oauth_error: Some(oauth_error),
+            ..
+        } => oauth_error == INVALID_GRANT_ERROR,
+        _ => false,
+    }
+}
+
+/// Records a failed attempt to refresh the user's access token.  Only failures caused by Spotify rejecting the refresh
+/// token are counted, disabling the user once they've hit the maximum number of consecutive failures, and the user's
+/// last update time is bumped so that they're moved to the back of the update queue.  Transient failures only have
+/// their error recorded, returning `None` since the refresh should be retried later; otherwise returns `Some(true)` if
+/// the user was disabled.
+pub fn record_token_refresh_failure(
+    conn: &DbConn,
+    user: &User,
+    err: &Error,
+) -> Result<Option<bool>, Error> {
+    use crate::schema::users::dsl::*;
+
+    if !is_refresh_token_rejected(err) {
+        diesel::update(users.filter(id.eq(user.id)))
+            .set(last_refresh_error.eq(Some(err.to_string())))
+            .execute(&conn.0)
+            .map_err(|err| -> Error {
+                error!("Error recording token refresh failure for user: {:?}", err);
+                Error::database("Error recording token refresh failure for user", err)
+            })?;
+        return Ok(None);
+    }
+
+    let failure_count = user.refresh_failure_count + 1;
+    let should_disable = failure_count >= crate::conf::CONF.max_token_refresh_failures;
+
+    diesel::update(users.filter(id.eq(user.id)))
+        .set((
+            refresh_failure_count.eq(failure_count),
+            last_refresh_error.eq(Some(err.to_string())),
+            disabled.eq(should_disable),
+            last_update_time.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .execute(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error recording token refresh failure for user: {:?}", err);
+            Error::database("Error recording token refresh failure for user", err)
+        })?;
+
+    Ok(Some(should_disable))
+}
+
+/// Stores the result of a successful token refresh for the user, including the rotated refresh token if Spotify
+/// returned one, and clears any recorded token refresh failures.  The provided `User` is updated to match.
+pub fn store_refreshed_user_token(
+    conn: &DbConn,
+    user: &mut User,
+    res: AccessTokenResponse,
+) -> Result<(), Error> {
+    use crate::schema::users::dsl::*;
+
+    let expires_at =
+        chrono::Utc::now().naive_utc() + chrono::Duration::seconds(res.expires_in as i64);
+    let new_refresh_token = res
+        .refresh_token
+        .unwrap_or_else(|| user.refresh_token.clone());
+
+    diesel::update(users.filter(id.eq(user.id)))
+        .set((
+            token.eq(&res.access_token),
+            refresh_token.eq(&new_refresh_token),
+            access_token_expires_at.eq(Some(expires_at)),
+            refresh_failure_count.eq(0),
+            last_refresh_error.eq(None::<String>),
+        ))
+        .execute(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error storing refreshed access token for user: {:?}", err);
+            Error::database("Error updating user with new access token", err)
+        })?;
+
+    user.token = res.access_token;
+    user.refresh_token = new_refresh_token;
+    user.access_token_expires_at = Some(expires_at);
+    user.refresh_failure_count = 0;
+    user.last_refresh_error = None;
+    Ok(())
+}
+
+/// Stores fresh tokens for a user that has gone through the OAuth flow again and re-enables them if they had been
+/// disabled.  Returns the number of rows updated.
+pub fn reauthorize_user(
+    conn: &DbConn,
+    user_spotify_id: &str,
+    access_token: &str,
+    new_refresh_token: &str,
+    expires_at: NaiveDateTime,
+) -> Result<usize, Error> {
+    use crate::schema::users::dsl::*;
+
+    diesel::update(users.filter(spotify_id.eq(user_spotify_id)))
+        .set((
+            token.eq(access_token),
+            refresh_token.eq(new_refresh_token),
+            access_token_expires_at.eq(Some(expires_at)),
+            refresh_failure_count.eq(0),
+            last_refresh_error.eq(None::<String>),
+            disabled.eq(false),
+        ))
+        .execute(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error storing tokens for re-authorized user: {:?}", err);
+            Error::database("Error storing tokens for re-authorized user", err)
+        })
+}
+
+#[derive(Debug, Default)]
+pub struct PartialSnapshotRepairReport {
+    /// Updates that had rank rows stored but no `stats_updates` entry, meaning that storing them failed partway through
+    pub orphaned_updates_removed: usize,
+    /// Updates that stored rankings for only one of artists or tracks
+    pub partial_updates_removed: usize,
+    /// Timeframes from removed partial updates whose rankings were still current as of the following update and were
+    /// moved onto that update rather than being deleted
+    pub timeframes_moved: usize,
+    pub users_reset: usize,
+}
+
+const ALL_TIMEFRAMES_MASK: u8 = 0b111;
+
+/// Returns `true` if the update looks like a snapshot that failed to store halfway through before snapshots were
+/// stored atomically.  Those always stored every timeframe for artists and then crashed before storing any tracks (or
+/// vice versa).  Only meaningful for updates stored before the atomic snapshots cutover: since only changed timeframes
+/// are stored, later updates can legitimately have these masks.
+fn is_partial_update(update: &StatsUpdate) -> bool {
+    match (update.artist_timeframes, update.track_timeframes) {
+        (ALL_TIMEFRAMES_MASK, 0) | (0, ALL_TIMEFRAMES_MASK) => true,
+        _ => false,
+    }
+}
+
+/// Deletes the rank rows stored for a user's update, optionally restricted to a single timeframe.
+fn delete_update_rank_rows(
+    conn: &DbConn,
+    entity: SnapshotEntity,
+    target_user_id: i64,
+    target_update_time: NaiveDateTime,
+    target_timeframe: Option<u8>,
+) -> Result<usize, diesel::result::Error> {
+    match entity {
+        SnapshotEntity::Artists => {
+            use crate::schema::artist_rank_snapshots::dsl::*;
+
+            let mut query = diesel::delete(artist_rank_snapshots)
+                .filter(user_id.eq(target_user_id))
+                .filter(update_time.eq(target_update_time))
+                .into_boxed();
+            if let Some(target_timeframe) = target_timeframe {
+                query = query.filter(timeframe.eq(target_timeframe));
+            }
+            query.execute(&conn.0)
+        }
+        SnapshotEntity::Tracks => {
+            use crate::schema::track_rank_snapshots::dsl::*;
+
+            let mut query = diesel::delete(track_rank_snapshots)
+                .filter(user_id.eq(target_user_id))
+                .filter(update_time.eq(target_update_time))
+                .into_boxed();
+            if let Some(target_timeframe) = target_timeframe {
+                query = query.filter(timeframe.eq(target_timeframe));
+            }
+            query.execute(&conn.0)
+        }
+    }
+}
+
+/// Moves the rank rows stored for one timeframe of a user's update onto a different update.
+fn move_update_rank_rows(
+    conn: &DbConn,
+    entity: SnapshotEntity,
+    target_user_id: i64,
+    from_update_time: NaiveDateTime,
+    to_update_time: NaiveDateTime,
+    target_timeframe: u8,
+) -> Result<usize, diesel::result::Error> {
+    match entity {
+        SnapshotEntity::Artists => {
+            use crate::schema::artist_rank_snapshots::dsl::*;
+
+            diesel::update(
+                artist_rank_snapshots
+                    .filter(user_id.eq(target_user_id))
+                    .filter(update_time.eq(from_update_time))
+                    .filter(timeframe.eq(target_timeframe)),
+            )
+            .set(update_time.eq(to_update_time))
+            .execute(&conn.0)
+        }
+        SnapshotEntity::Tracks => {
+            use crate::schema::track_rank_snapshots::dsl::*;
+
+            diesel::update(
+                track_rank_snapshots
+                    .filter(user_id.eq(target_user_id))
+                    .filter(update_time.eq(from_update_time))
+                    .filter(timeframe.eq(target_timeframe)),
+            )
+            .set(update_time.eq(to_update_time))
+            .execute(&conn.0)
+        }
+    }
+}
+
+/// Returns the time at which snapshots started being stored atomically, as recorded by the migration that shipped
+/// with that change.  Only updates stored before then can have been left half-written.
+fn get_atomic_snapshots_cutover_time(conn: &DbConn) -> Result<NaiveDateTime, Error> {
+    use crate::schema::snapshot_cutovers::dsl::*;
+
+    snapshot_cutovers
+        .find("atomic_snapshots")
+        .select(cutover_time)
+        .first(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error querying atomic snapshots cutover time: {:?}", err);
+            Error::database(
+                "Error querying atomic snapshots cutover time from database",
+                err,
+            )
+        })
+}
+
+/// Finds and removes snapshots that were only partially stored before snapshots were stored atomically:
+///
+///  - Rank rows with no matching `stats_updates` entry are deleted.  Nothing reads them, since the entry was the last
+///    thing to be written for an update.
+///  - Updates stored before the atomic snapshots cutover that stored only artists or only tracks are removed.
+///    Rankings stored by them that were carried forward by the following update are moved onto it so that later
+///    updates stay intact.  Later updates are never touched since the same masks are legitimate for them.
+///
+/// Users whose last update was removed have their last update time reset to their latest remaining update.  Each
+/// user is repaired in their own transaction.  If `dry_run` is set, nothing is changed and the returned report
+/// describes what would have been done.
+pub fn repair_partial_snapshots(
+    conn: &DbConn,
+    dry_run: bool,
+) -> Result<PartialSnapshotRepairReport, Error> {
+    use crate::schema::users;
+
+    let cutover_time = get_atomic_snapshots_cutover_time(conn)?;
+    let all_user_ids: Vec<i64> =
+        users::table
+            .select(users::id)
+            .load(&conn.0)
+            .map_err(|err| -> Error {
+                error!("Error querying user ids from database: {:?}", err);
+                Error::database("Error querying user ids from database", err)
+            })?;
+
+    let mut report = PartialSnapshotRepairReport::default();
+    for target_user_id in all_user_ids {
+        with_transaction(conn, || {
+            repair_user_partial_snapshots(conn, target_user_id, cutover_time, dry_run, &mut report)
+                .map_err(|err| -> Error {
+                    error!(
+                        "Error repairing partial snapshots for user {}: {:?}",
+                        target_user_id, err
+                    );
+                    Error::database("Error repairing partial snapshots", err)
+                })
+        })?;
+    }
+
+    Ok(report)
+}
+
+fn repair_user_partial_snapshots(
+    conn: &DbConn,
+    target_user_id: i64,
+    cutover_time: NaiveDateTime,
+    dry_run: bool,
+    report: &mut PartialSnapshotRepairReport,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::{artist_rank_snapshots, stats_updates, track_rank_snapshots, users};
+
+    let mut removed_update_times: HashSet<NaiveDateTime> = HashSet::new();
+
+    let mut updates: Vec<StatsUpdate> = stats_updates::table
+        .filter(stats_updates::user_id.eq(target_user_id))
+        .order_by(stats_updates::update_time)
+        .load(&conn.0)?;
+    let known_update_times: HashSet<NaiveDateTime> =
+        updates.iter().map(|update| update.update_time).collect();
+
+    // Rank rows that were stored without a corresponding update
+    let mut stored_update_times: HashSet<NaiveDateTime> = artist_rank_snapshots::table
+        .filter(artist_rank_snapshots::user_id.eq(target_user_id))
+        .select(artist_rank_snapshots::update_time)
+        .distinct()
+        .load::<NaiveDateTime>(&conn.0)?
+        .into_iter()
+        .collect();
+    stored_update_times.extend(
+        track_rank_snapshots::table
+            .filter(track_rank_snapshots::user_id.eq(target_user_id))
+            .select(track_rank_snapshots::update_time)
+            .distinct()
+            .load::<NaiveDateTime>(&conn.0)?,
+    );
+    for orphan_update_time in stored_update_times.difference(&known_update_times) {
+        if !dry_run {
+            for &entity in &[SnapshotEntity::Artists, SnapshotEntity::Tracks] {
+                delete_update_rank_rows(conn, entity, target_user_id, *orphan_update_time, None)?;
+            }
+        }
+        removed_update_times.insert(*orphan_update_time);
+        report.orphaned_updates_removed += 1;
+    }
+
+    // Updates that only stored one of artists or tracks.  Masks are updated as rankings are moved forward, so a
+    // partial update followed by one holding the other half gets merged into a single complete update.
+    let mut removed_update_ids: HashSet<i64> = HashSet::new();
+    for i in 0..updates.len() {
+        if updates[i].update_time >= cutover_time || !is_partial_update(&updates[i]) {
+            continue;
+        }
+
+        let update = updates[i].clone();
+        let next_index = Some(i + 1).filter(|&next_index| next_index < updates.len());
+        for &entity in &[SnapshotEntity::Artists, SnapshotEntity::Tracks] {
+            for timeframe_id in 0..3u8 {
+                if !entity.is_stored(&update, timeframe_id) {
+                    continue;
+                }
+
+                match next_index {
+                    Some(next_index) if !entity.is_stored(&updates[next_index], timeframe_id) => {
+                        let next_update = &mut updates[next_index];
+                        if !dry_run {
+                            move_update_rank_rows(
+                                conn,
+                                entity,
+                                target_user_id,
+                                update.update_time,
+                                next_update.update_time,
+                                timeframe_id,
+                            )?;
+                        }
+                        match entity {
+                            SnapshotEntity::Artists => {
+                                next_update.artist_timeframes |= 1 << timeframe_id
+                            }
+                            SnapshotEntity::Tracks => {
+                                next_update.track_timeframes |= 1 << timeframe_id
+                            }
+                        }
+                        report.timeframes_moved += 1;
+                    }
+                    _ if !dry_run => {
+                        delete_update_rank_rows(
+                            conn,
+                            entity,
+                            target_user_id,
+                            update.update_time,
+                            Some(timeframe_id),
+                        )?;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        if !dry_run {
+            if let Some(next_index) = next_index {
+                let next_update = &updates[next_index];
+                diesel::update(stats_updates::table.find(next_update.id))
+                    .set((
+                        stats_updates::artist_timeframes.eq(next_update.artist_timeframes),
+                        stats_updates::track_timeframes.eq(next_update.track_timeframes),
+                    ))
+                    .execute(&conn.0)?;
+            }
+            diesel::delete(stats_updates::table.find(update.id)).execute(&conn.0)?;
+        }
+        removed_update_ids.insert(update.id);
+        removed_update_times.insert(update.update_time);
+        report.partial_updates_removed += 1;
+    }
+
+    // Reset the user's last update time if their last update was removed so that they get updated again
+    if removed_update_times.is_empty() {
+        return Ok(());
+    }
+    let cur_last_update_time: NaiveDateTime = users::table
+        .find(target_user_id)
+        .select(users::last_update_time)
+        .first(&conn.0)?;
+    if !removed_update_times.contains(&cur_last_update_time) {
+        return Ok(());
+    }
+
+    let latest_remaining_update_time = updates
+        .iter()
+        .filter(|update| !removed_update_ids.contains(&update.id))
+        .map(|update| update.update_time)
+        .max();
+    if let Some(latest_remaining_update_time) = latest_remaining_update_time {
+        if !dry_run {
+            diesel::update(users::table.find(target_user_id))
+                .set(users::last_update_time.eq(latest_remaining_update_time))
+                .execute(&conn.0)?;
+        }
+        report.users_reset += 1;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct AccountDeletionReport {
+    pub artist_snapshots_removed: usize,
+    pub track_snapshots_removed: usize,
+    pub stats_updates_removed: usize,
+    pub play_events_removed: usize,
+    /// Artists and tracks that were only referenced by the deleted user
+    pub spotify_items_removed: usize,
+    pub track_artist_mappings_removed: usize,
+    pub artist_genre_mappings_removed: usize,
+}
+
+/// Maximum number of IDs to include in a single `IN` clause while purging a user's data
+const ACCOUNT_DELETION_CHUNK_SIZE: usize = 5000;
+
+/// Returns all of the provided mapped Spotify IDs that are still referenced by some user's rank snapshots or play
+/// events, along with the artists of any of those that are tracks.  These are locking reads so that they see rows
+/// committed since the calling transaction's snapshot was taken.
+fn get_referenced_spotify_items(
+    conn: &DbConn,
+    candidate_ids: &[i32],
+) -> Result<HashSet<i32>, diesel::result::Error> {
+    use crate::schema::{artist_rank_snapshots, play_events, track_rank_snapshots, tracks_artists};
+
+    let mut referenced_ids = HashSet::new();
+    for chunk in candidate_ids.chunks(ACCOUNT_DELETION_CHUNK_SIZE) {
+        referenced_ids.extend(
+            artist_rank_snapshots::table
+                .filter(artist_rank_snapshots::mapped_spotify_id.eq_any(chunk))
+                .select(artist_rank_snapshots::mapped_spotify_id)
+                .distinct()
+                .for_update()
+                .load::<i32>(&conn.0)?,
+        );
+        referenced_ids.extend(
+            track_rank_snapshots::table
+                .filter(track_rank_snapshots::mapped_spotify_id.eq_any(chunk))
+                .select(track_rank_snapshots::mapped_spotify_id)
+                .distinct()
+                .for_update()
+                .load::<i32>(&conn.0)?,
+        );
+        referenced_ids.extend(
+            play_events::table
+                .filter(play_events::mapped_spotify_id.eq_any(chunk))
+                .select(play_events::mapped_spotify_id)
+                .distinct()
+                .for_update()
+                .load::<i32>(&conn.0)?,
+        );
+    }
+
+    // Artists that are only referenced as the artist of a track somebody has are still needed for that track
+    let referenced_track_ids: Vec<i32> = referenced_ids.iter().copied().collect();
+    for chunk in referenced_track_ids.chunks(ACCOUNT_DELETION_CHUNK_SIZE) {
+        referenced_ids.extend(
+            tracks_artists::table
+                .filter(tracks_artists::track_id.eq_any(chunk))
+                .select(tracks_artists::artist_id)
+                .distinct()
+                .for_update()
+                .load::<i32>(&conn.0)?,
+        );
+    }
+
+    Ok(referenced_ids)
+}
+
+/// Permanently deletes a user along with all of their rank snapshots, updates, and play events.  Artists and tracks
+/// that no other user references are removed as well, along with their track/artist and artist/genre mappings (their
+/// global stats history is removed by the `spotify_items` foreign key cascade).  The user's stored Spotify tokens are
+/// deleted with their row; Spotify has no endpoint for revoking them, so users that want to revoke the app's access
+/// entirely need to do so from their Spotify account page.  All of this happens in a single transaction.
+pub fn delete_user_account(conn: &DbConn, user: &User) -> Result<AccountDeletionReport, Error> {
+    with_transaction(conn, || {
+        delete_user_account_inner(conn, user).map_err(|err| -> Error {
+            error!(
+                "Error deleting account for user {}: {:?}",
+                user.username, err
+            );
+            Error::database("Error deleting user account", err)
+        })
+    })
+}
+
+fn delete_user_account_inner(
+    conn: &DbConn,
+    user: &User,
+) -> Result<AccountDeletionReport, diesel::result::Error> {
+    use crate::schema::{
+        artist_rank_snapshots, artists_genres, play_events, spotify_items, stats_updates,
+        track_rank_snapshots, tracks_artists, users,
+    };
+
+    let mut report = AccountDeletionReport::default();
+
+    // Everything that the user references is a candidate for removal once they're gone
+    let mut candidate_ids: HashSet<i32> = artist_rank_snapshots::table
+        .filter(artist_rank_snapshots::user_id.eq(user.id))
+        .select(artist_rank_snapshots::mapped_spotify_id)
+        .distinct()
+        .load::<i32>(&conn.0)?
+        .into_iter()
+        .collect();
+    candidate_ids.extend(
+        track_rank_snapshots::table
+            .filter(track_rank_snapshots::user_id.eq(user.id))
+            .select(track_rank_snapshots::mapped_spotify_id)
+            .distinct()
+            .load::<i32>(&conn.0)?,
+    );
+    candidate_ids.extend(
+        play_events::table
+            .filter(play_events::user_id.eq(user.id))
+            .select(play_events::mapped_spotify_id)
+            .distinct()
+            .load::<i32>(&conn.0)?,
+    );
+    let candidate_track_ids: Vec<i32> = candidate_ids.iter().copied().collect();
+    for chunk in candidate_track_ids.chunks(ACCOUNT_DELETION_CHUNK_SIZE) {
+        candidate_ids.extend(
+            tracks_artists::table
+                .filter(tracks_artists::track_id.eq_any(chunk))
+                .select(tracks_artists::artist_id)
+                .distinct()
+                .load::<i32>(&conn.0)?,
+        );
+    }
+
+    report.artist_snapshots_removed = diesel::delete(
+        artist_rank_snapshots::table.filter(artist_rank_snapshots::user_id.eq(user.id)),
+    )
+    .execute(&conn.0)?;
+    report.track_snapshots_removed = diesel::delete(
+        track_rank_snapshots::table.filter(track_rank_snapshots::user_id.eq(user.id)),
+    )
+    .execute(&conn.0)?;
+    report.stats_updates_removed =
+        diesel::delete(stats_updates::table.filter(stats_updates::user_id.eq(user.id)))
+            .execute(&conn.0)?;
+    report.play_events_removed =
+        diesel::delete(play_events::table.filter(play_events::user_id.eq(user.id)))
+            .execute(&conn.0)?;
+    diesel::delete(users::table.find(user.id)).execute(&conn.0)?;
+
+    // Lock the candidates before checking whether anything still references them.  Inserting a row that references a
+    // `spotify_items` row takes a shared lock on it for the foreign key check, so concurrent updates and imports that
+    // start referencing one of them either finish before the check sees them or wait until this transaction is done.
+    // Locking in ID order keeps concurrent deletions from deadlocking each other.
+    let mut candidate_ids: Vec<i32> = candidate_ids.into_iter().collect();
+    candidate_ids.sort_unstable();
+    for chunk in candidate_ids.chunks(ACCOUNT_DELETION_CHUNK_SIZE) {
+        spotify_items::table
+            .filter(spotify_items::id.eq_any(chunk))
+            .select(spotify_items::id)
+            .order_by(spotify_items::id)
+            .for_update()
+            .load::<i32>(&conn.0)?;
+    }
+    let referenced_ids = get_referenced_spotify_items(conn, &candidate_ids)?;
+    let orphaned_ids: Vec<i32> = candidate_ids
+        .into_iter()
+        .filter(|candidate_id| !referenced_ids.contains(candidate_id))
+        .collect();
+    for chunk in orphaned_ids.chunks(ACCOUNT_DELETION_CHUNK_SIZE) {
+        report.track_artist_mappings_removed += diesel::delete(
+            tracks_artists::table.filter(
+                tracks_artists::track_id
+                    .eq_any(chunk)
+                    .or(tracks_artists::artist_id.eq_any(chunk)),
+            ),
+        )
+        .execute(&conn.0)?;
+        report.artist_genre_mappings_removed +=
+            diesel::delete(artists_genres::table.filter(artists_genres::artist_id.eq_any(chunk)))
+                .execute(&conn.0)?;
+        report.spotify_items_removed +=
+            diesel::delete(spotify_items::table.filter(spotify_items::id.eq_any(chunk)))
+                .execute(&conn.0)?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+pub fn test_update(hour: u32, artist_timeframes: u8, track_timeframes: u8) -> StatsUpdate {
+    StatsUpdate {
+        id: hour as i64,
+        user_id: 1,
+        update_time: chrono::NaiveDate::from_ymd(2020, 7, 1).and_hms(hour, 0, 0),
+        artist_timeframes,
+        track_timeframes,
+    }
+}
+
+#[test]
+fn timeframes_carried_forward() {
+    let updates = vec![
+        // First update stores every timeframe
+        test_update(0, 0b111, 0b111),
+        // Nothing changed
+        test_update(1, 0b000, 0b111),
+        // Only the medium timeframe changed
+        test_update(2, 0b010, 0b000),
+    ];
+    let stored = || {
+        let mut stored: HashMap<NaiveDateTime, TimeFrames<u32>> = HashMap::new();
+        stored.insert(
+            updates[0].update_time,
+            TimeFrames {
+                short: vec![1, 2],
+                medium: vec![3],
+                long: vec![4],
+            },
+        );
+        stored.insert(
+            updates[2].update_time,
+            TimeFrames {
+                short: Vec::new(),
+                medium: vec![5, 6],
+                long: Vec::new(),
+            },
+        );
+        stored
+    };
+    let summarize = |history: Vec<(NaiveDateTime, TimeFrames<u32>)>| {
+        history
+            .into_iter()
+            .map(|(update_time, timeframes)| {
+                (
+                    update_time,
+                    [timeframes.short, timeframes.medium, timeframes.long],
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let history = carry_forward_timeframes(
+        &updates,
+        SnapshotEntity::Artists,
+        &HistoryFilter::default(),
+        stored(),
+    );
+    assert_eq!(
+        summarize(history),
+        vec![
+            (updates[0].update_time, [vec![1, 2], vec![3], vec![4]]),
+            (updates[1].update_time, [vec![1, 2], vec![3], vec![4]]),
+            (updates[2].update_time, [vec![1, 2], vec![5, 6], vec![4]]),
+        ]
+    );
+
+    // Rankings stored before `from` are still carried into the filtered range
+    let filter = HistoryFilter {
+        from: Some(updates[1].update_time),
+        ..HistoryFilter::default()
+    };
+    let history = carry_forward_timeframes(&updates, SnapshotEntity::Artists, &filter, stored());
+    assert_eq!(
+        summarize(history),
+        vec![
+            (updates[1].update_time, [vec![1, 2], vec![3], vec![4]]),
+            (updates[2].update_time, [vec![1, 2], vec![5, 6], vec![4]]),
+        ]
+    );
+
+    // Nothing has been stored for tracks until the second update
+    let history = carry_forward_timeframes(
+        &[test_update(0, 0b111, 0b000), test_update(1, 0b000, 0b111)],
+        SnapshotEntity::Tracks,
+        &HistoryFilter::default(),
+        stored(),
+    );
+    assert!(history.is_empty());
+}
+
+#[test]
+fn rankings_carried_forward() {
+    let updates = vec![
+        // Only tracks were stored, so the artist doesn't have any rankings yet
+        test_update(0, 0b000, 0b111),
+        test_update(1, 0b111, 0b000),
+        // Nothing changed
+        test_update(2, 0b000, 0b000),
+        // The artist dropped out of the short timeframe
+        test_update(3, 0b001, 0b000),
+        // ...and then out of the medium timeframe
+        test_update(4, 0b010, 0b000),
+        // ...and then entered the long timeframe
+        test_update(5, 0b100, 0b000),
+    ];
+    let stored_ranking = |update: &StatsUpdate, timeframe: u8, ranking: u16| RankHistoryResItem {
+        update_time: update.update_time,
+        ranking,
+        timeframe,
+    };
+    let stored_rankings = vec![
+        stored_ranking(&updates[1], 0, 3),
+        stored_ranking(&updates[1], 1, 5),
+        stored_ranking(&updates[5], 2, 7),
+    ];
+
+    let history = carry_forward_rankings(
+        &updates,
+        SnapshotEntity::Artists,
+        &HistoryFilter::default(),
+        stored_rankings,
+    );
+    assert_eq!(
+        history,
+        vec![
+            (updates[1].update_time, [Some(3), Some(5), None]),
+            (updates[2].update_time, [Some(3), Some(5), None]),
+            (updates[3].update_time, [None, Some(5), None]),
+            (updates[5].update_time, [None, None, Some(7)]),
+        ]
+    );
+}
+
+#[test]
+fn refresh_token_rejection_detection() {
+    let oauth_error = |code: &str| Error::SpotifyApi {
+        status: Some(400),
+        message: format!("{}: Refresh token revoked", code),
+        oauth_error: Some(code.into()),
+    };
+
+    assert!(is_refresh_token_rejected(&oauth_error("invalid_grant")));
+    assert!(!is_refresh_token_rejected(&oauth_error("invalid_client")));
+    // Only the OAuth error code counts, not messages that happen to mention it
+    assert!(!is_refresh_token_rejected(&Error::spotify_api(
+        Some(400),
+        "invalid_grant"
+    )));
+    assert!(!is_refresh_token_rejected(&Error::RateLimited {
+        retry_after: None
+    }));
+}
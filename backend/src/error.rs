@@ -0,0 +1,215 @@
+//! Error type shared by the whole backend.  Errors keep their underlying cause around for logging and map to an HTTP
+//! status along with a JSON body when returned from a route.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::Cursor;
+use std::time::Duration;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+pub type BoxedError = Box<dyn StdError + Send + Sync>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A database query failed or no database connection was available
+    Database {
+        message: String,
+        source: BoxedError,
+    },
+    /// Reading from or writing to the Redis metadata cache failed
+    Cache {
+        message: String,
+        source: BoxedError,
+    },
+    /// A request to the Spotify API couldn't be completed or its response couldn't be decoded
+    Http {
+        message: String,
+        source: BoxedError,
+    },
+    /// The Spotify API responded with an error
+    SpotifyApi {
+        status: Option<u16>,
+        message: String,
+        /// OAuth error code such as `invalid_grant`; only set for errors from the Spotify accounts service
+        oauth_error: Option<String>,
+    },
+    /// The Spotify API was still rate limiting us after all retries were used up
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    NotFound(String),
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Internal(String),
+}
+
+impl Error {
+    pub fn database(message: impl Into<String>, source: impl Into<BoxedError>) -> Self {
+        Error::Database {
+            message: message.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn cache(message: impl Into<String>, source: impl Into<BoxedError>) -> Self {
+        Error::Cache {
+            message: message.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn http(message: impl Into<String>, source: impl Into<BoxedError>) -> Self {
+        Error::Http {
+            message: message.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn spotify_api(status: Option<u16>, message: impl Into<String>) -> Self {
+        Error::SpotifyApi {
+            status,
+            message: message.into(),
+            oauth_error: None,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            Error::Database { .. } | Error::Cache { .. } => Status::ServiceUnavailable,
+            Error::Http { .. } | Error::SpotifyApi { .. } => Status::BadGateway,
+            Error::RateLimited { .. } => Status::TooManyRequests,
+            Error::NotFound(_) => Status::NotFound,
+            Error::BadRequest(_) => Status::BadRequest,
+            Error::Unauthorized(_) => Status::Unauthorized,
+            Error::Forbidden(_) => Status::Forbidden,
+            Error::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    /// Machine-readable identifier for the kind of error, included in error response bodies
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Database { .. } => "database_error",
+            Error::Cache { .. } => "cache_error",
+            Error::Http { .. } => "spotify_unreachable",
+            Error::SpotifyApi { .. } => "spotify_error",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::NotFound(_) => "not_found",
+            Error::BadRequest(_) => "bad_request",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    /// Only includes the message meant for clients; the source can be retrieved with `Error::source` or `Debug`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Database { message, .. }
+            | Error::Cache { message, .. }
+            | Error::Http { message, .. }
+            | Error::SpotifyApi { message, .. }
+            | Error::NotFound(message)
+            | Error::BadRequest(message)
+            | Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::Internal(message) => write!(f, "{}", message),
+            Error::RateLimited { .. } => write!(f, "Rate limited by the Spotify API"),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Database { source, .. }
+            | Error::Cache { source, .. }
+            | Error::Http { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spotify_status: Option<u16>,
+}
+
+impl<'r> Responder<'r> for Error {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        let retry_after = match &self {
+            Error::RateLimited { retry_after } => retry_after.map(|delay| delay.as_secs()),
+            _ => None,
+        };
+        let body = ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+            retry_after_seconds: retry_after,
+            spotify_status: match &self {
+                Error::SpotifyApi { status, .. } => *status,
+                _ => None,
+            },
+        };
+        let body = serde_json::to_string(&body).map_err(|err| {
+            error!("Error serializing error response body: {:?}", err);
+            Status::InternalServerError
+        })?;
+
+        let mut res = Response::build();
+        res.status(self.status())
+            .header(ContentType::JSON)
+            .sized_body(Cursor::new(body));
+        if let Some(retry_after) = retry_after {
+            res.header(Header::new("Retry-After", retry_after.to_string()));
+        }
+        res.ok()
+    }
+}
+
+// Catchers that render errors which don't come from a route, such as failed request guards or requests that didn't
+// match any route, with the same JSON body as errors returned from routes.
+
+#[catch(400)]
+pub fn bad_request(_: &Request) -> Error {
+    Error::BadRequest("The request was malformed".into())
+}
+
+#[catch(401)]
+pub fn unauthorized(_: &Request) -> Error {
+    Error::Unauthorized("A valid Spotify access token must be provided as a bearer token".into())
+}
+
+#[catch(404)]
+pub fn not_found(_: &Request) -> Error {
+    Error::NotFound("Nothing was found for the request".into())
+}
+
+#[catch(500)]
+pub fn internal_error(_: &Request) -> Error {
+    Error::Internal("An internal error occurred".into())
+}
+
+#[catch(502)]
+pub fn bad_gateway(_: &Request) -> Error {
+    Error::spotify_api(None, "Error communicating with the Spotify API")
+}
+
+/// Rocket fails the `DbConn` request guard with a 503 when no database connection can be checked out of the pool
+#[catch(503)]
+pub fn service_unavailable(_: &Request) -> Error {
+    Error::database(
+        "The service is temporarily unavailable",
+        "No database connection available",
+    )
+}
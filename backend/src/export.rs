@@ -0,0 +1,501 @@
+//! Streaming export of all of the artist and track rank snapshots stored for a user.  Snapshots are read from the
+//! database a page of updates at a time and written out as they're read so that the full history never has to be held
+//! in memory.
+
+use std::io::{self, Read};
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use hashbrown::HashMap;
+use rocket::http::{ContentType, RawStr};
+use rocket::request::FromFormValue;
+
+use crate::db_util::{HistoryFilter, SnapshotEntity};
+use crate::error::Error;
+use crate::models::{HasSpotifyId, StatsUpdate, TimeFrames, User};
+use crate::DbConn;
+
+/// Number of updates to load from the database at a time
+const EXPORT_PAGE_SIZE: i64 = 10;
+const TIMEFRAME_NAMES: [&str; 3] = ["short", "medium", "long"];
+const CSV_HEADER: &str =
+    "entity_type,update_time,timeframe,ranking,stored,spotify_id,name,artists,genres\n";
+
+#[derive(Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Csv,
+    JsonLines,
+}
+
+impl ExportFormat {
+    pub fn get_content_type(self) -> ContentType {
+        match self {
+            ExportFormat::Csv => ContentType::CSV,
+            ExportFormat::JsonLines => ContentType::new("application", "x-ndjson"),
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for ExportFormat {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" | "json" => Ok(ExportFormat::JsonLines),
+            _ => Err(form_value),
+        }
+    }
+}
+
+#[derive(Queryable, Serialize, Clone)]
+struct SnapshotRow {
+    update_time: NaiveDateTime,
+    timeframe: u8,
+    ranking: u16,
+    mapped_spotify_id: i32,
+    spotify_id: String,
+}
+
+#[derive(Serialize)]
+struct ExportRow<'a> {
+    entity_type: &'static str,
+    update_time: NaiveDateTime,
+    timeframe: &'static str,
+    ranking: u16,
+    /// `false` if the timeframe's rankings didn't change in this update and were carried forward from an earlier one
+    stored: bool,
+    spotify_id: &'a str,
+    name: &'a str,
+    artists: Vec<&'a str>,
+    genres: &'a [String],
+}
+
+fn escape_csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.into()
+    }
+}
+
+/// Returns every ranked item in a carried-forward history along with the time of the update it's ranked as of
+fn history_rows(
+    history: &[(NaiveDateTime, TimeFrames<SnapshotRow>)],
+) -> impl Iterator<Item = (NaiveDateTime, &SnapshotRow)> {
+    history.iter().flat_map(|(update_time, timeframes)| {
+        timeframes
+            .iter()
+            .flat_map(|(_timeframe, rows)| rows.iter())
+            .map(move |row| (*update_time, row))
+    })
+}
+
+/// Implements `Read` over all of a user's rank snapshots.  Every update is exported in full, including the timeframes
+/// that weren't stored for it because they were unchanged since an earlier update.  Updates are exported a page at a
+/// time in the order that they happened, with the artists for each page followed by its tracks.
+pub struct SnapshotExportReader {
+    conn: DbConn,
+    user_id: i64,
+    spotify_access_token: String,
+    format: ExportFormat,
+    /// Time of the last update that was exported
+    cursor: Option<NaiveDateTime>,
+    /// Rankings as of the last update that was exported, which are carried forward into the next page of updates
+    cur_artist_rankings: TimeFrames<SnapshotRow>,
+    cur_track_rankings: TimeFrames<SnapshotRow>,
+    buf: Vec<u8>,
+    buf_pos: usize,
+    done: bool,
+}
+
+impl SnapshotExportReader {
+    pub fn new(
+        conn: DbConn,
+        user: &User,
+        spotify_access_token: String,
+        format: ExportFormat,
+    ) -> Self {
+        let buf = match format {
+            ExportFormat::Csv => CSV_HEADER.as_bytes().to_vec(),
+            ExportFormat::JsonLines => Vec::new(),
+        };
+
+        SnapshotExportReader {
+            conn,
+            user_id: user.id,
+            spotify_access_token,
+            format,
+            cursor: None,
+            cur_artist_rankings: TimeFrames::default(),
+            cur_track_rankings: TimeFrames::default(),
+            buf,
+            buf_pos: 0,
+            done: false,
+        }
+    }
+
+    fn load_updates_page(&self) -> Result<Vec<StatsUpdate>, Error> {
+        use crate::schema::stats_updates::dsl::*;
+
+        let mut query = stats_updates
+            .filter(user_id.eq(self.user_id))
+            .order_by(update_time.asc())
+            .limit(EXPORT_PAGE_SIZE)
+            .into_boxed();
+        if let Some(cursor) = self.cursor {
+            query = query.filter(update_time.gt(cursor));
+        }
+
+        query
+            .load::<StatsUpdate>(&self.conn.0)
+            .map_err(|err| -> Error {
+                error!("Error loading stats updates for export: {:?}", err);
+                Error::database("Error loading stats updates for export from database", err)
+            })
+    }
+
+    /// Loads the rank rows stored by the updates between `start` and `end`, grouped by update
+    fn load_stored_rankings(
+        &self,
+        entity: SnapshotEntity,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<HashMap<NaiveDateTime, TimeFrames<SnapshotRow>>, Error> {
+        use crate::schema::spotify_items;
+
+        let res = match entity {
+            SnapshotEntity::Artists => {
+                use crate::schema::artist_rank_snapshots::dsl::*;
+
+                artist_rank_snapshots
+                    .filter(user_id.eq(self.user_id))
+                    .filter(update_time.between(start, end))
+                    .order_by((update_time.asc(), timeframe.asc(), ranking.asc()))
+                    .inner_join(spotify_items::table)
+                    .select((
+                        update_time,
+                        timeframe,
+                        ranking,
+                        mapped_spotify_id,
+                        spotify_items::spotify_id,
+                    ))
+                    .load::<SnapshotRow>(&self.conn.0)
+            }
+            SnapshotEntity::Tracks => {
+                use crate::schema::track_rank_snapshots::dsl::*;
+
+                track_rank_snapshots
+                    .filter(user_id.eq(self.user_id))
+                    .filter(update_time.between(start, end))
+                    .order_by((update_time.asc(), timeframe.asc(), ranking.asc()))
+                    .inner_join(spotify_items::table)
+                    .select((
+                        update_time,
+                        timeframe,
+                        ranking,
+                        mapped_spotify_id,
+                        spotify_items::spotify_id,
+                    ))
+                    .load::<SnapshotRow>(&self.conn.0)
+            }
+        };
+        let rows = res.map_err(|err| -> Error {
+            error!("Error loading snapshots for export: {:?}", err);
+            Error::database("Error loading snapshots for export from database", err)
+        })?;
+
+        let mut stored_updates: HashMap<NaiveDateTime, TimeFrames<SnapshotRow>> = HashMap::new();
+        for row in rows {
+            stored_updates
+                .entry(row.update_time)
+                .or_insert_with(TimeFrames::default)
+                .add_item_by_id(row.timeframe, row);
+        }
+        Ok(stored_updates)
+    }
+
+    fn load_genres(&self, rows: &[&SnapshotRow]) -> Result<HashMap<i32, Vec<String>>, Error> {
+        use crate::schema::artists_genres::dsl::*;
+
+        let artist_ids: Vec<i32> = rows.iter().map(|row| row.mapped_spotify_id).collect();
+        let pairs = artists_genres
+            .filter(artist_id.eq_any(&artist_ids))
+            .select((artist_id, genre))
+            .load::<(i32, String)>(&self.conn.0)
+            .map_err(|err| -> Error {
+                error!("Error loading artist genres for export: {:?}", err);
+                Error::database("Error loading artist genres for export from database", err)
+            })?;
+
+        let mut genres_by_artist_id: HashMap<i32, Vec<String>> = HashMap::new();
+        for (pair_artist_id, pair_genre) in pairs {
+            genres_by_artist_id
+                .entry(pair_artist_id)
+                .or_insert_with(Vec::new)
+                .push(pair_genre);
+        }
+        Ok(genres_by_artist_id)
+    }
+
+    fn write_row(&mut self, row: &ExportRow) -> Result<(), Error> {
+        match self.format {
+            ExportFormat::JsonLines => {
+                serde_json::to_writer(&mut self.buf, row).map_err(|err| -> Error {
+                    error!("Error serializing export row: {:?}", err);
+                    Error::Internal("Error serializing export row".into())
+                })?;
+                self.buf.push(b'\n');
+            }
+            ExportFormat::Csv => {
+                let line = format!(
+                    "{},{},{},{},{},{},{},{},{}\n",
+                    row.entity_type,
+                    row.update_time,
+                    row.timeframe,
+                    row.ranking,
+                    row.stored,
+                    escape_csv_field(row.spotify_id),
+                    escape_csv_field(row.name),
+                    escape_csv_field(&row.artists.join(";")),
+                    escape_csv_field(&row.genres.join(";")),
+                );
+                self.buf.extend_from_slice(line.as_bytes());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Renders the full rankings of one type of entity as of each of the provided updates into the buffer along with
+    /// their metadata
+    fn write_rankings(
+        &mut self,
+        entity: SnapshotEntity,
+        updates_by_time: &HashMap<NaiveDateTime, &StatsUpdate>,
+        history: &[(NaiveDateTime, TimeFrames<SnapshotRow>)],
+    ) -> Result<(), Error> {
+        let rows: Vec<&SnapshotRow> = history_rows(history).map(|(_, row)| row).collect();
+        let mut spotify_ids: Vec<&str> = rows.iter().map(|row| row.spotify_id.as_str()).collect();
+        spotify_ids.sort_unstable();
+        spotify_ids.dedup();
+        let is_stored = |update_time: NaiveDateTime, timeframe_id: u8| {
+            updates_by_time
+                .get(&update_time)
+                .map(|update| entity.is_stored(update, timeframe_id))
+                .unwrap_or(false)
+        };
+
+        match entity {
+            SnapshotEntity::Artists => {
+                let genres_by_artist_id = self.load_genres(&rows)?;
+                let artists_by_id: HashMap<String, _> =
+                    crate::spotify_api::fetch_artists(&self.spotify_access_token, &spotify_ids)?
+                        .into_iter()
+                        .map(|artist| (artist.get_spotify_id().to_string(), artist))
+                        .collect();
+
+                for (update_time, row) in history_rows(history) {
+                    let name = artists_by_id
+                        .get(&row.spotify_id)
+                        .map(|artist| artist.name.as_str())
+                        .unwrap_or("");
+                    let genres = genres_by_artist_id
+                        .get(&row.mapped_spotify_id)
+                        .map(Vec::as_slice)
+                        .unwrap_or(&[]);
+                    self.write_row(&ExportRow {
+                        entity_type: "artist",
+                        update_time,
+                        timeframe: TIMEFRAME_NAMES[row.timeframe as usize],
+                        ranking: row.ranking,
+                        stored: is_stored(update_time, row.timeframe),
+                        spotify_id: &row.spotify_id,
+                        name,
+                        artists: Vec::new(),
+                        genres,
+                    })?;
+                }
+            }
+            SnapshotEntity::Tracks => {
+                let tracks_by_id: HashMap<String, _> =
+                    crate::spotify_api::fetch_tracks(&self.spotify_access_token, &spotify_ids)?
+                        .into_iter()
+                        .map(|track| (track.get_spotify_id().to_string(), track))
+                        .collect();
+
+                for (update_time, row) in history_rows(history) {
+                    let track = tracks_by_id.get(&row.spotify_id);
+                    self.write_row(&ExportRow {
+                        entity_type: "track",
+                        update_time,
+                        timeframe: TIMEFRAME_NAMES[row.timeframe as usize],
+                        ranking: row.ranking,
+                        stored: is_stored(update_time, row.timeframe),
+                        spotify_id: &row.spotify_id,
+                        name: track.map(|track| track.name.as_str()).unwrap_or(""),
+                        artists: track
+                            .map(|track| {
+                                track
+                                    .artists
+                                    .iter()
+                                    .map(|artist| artist.name.as_str())
+                                    .collect()
+                            })
+                            .unwrap_or_else(Vec::new),
+                        genres: &[],
+                    })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads the next page of updates into the buffer.  Rankings for timeframes that weren't stored by an update are
+    /// carried forward from the last update that stored them, which may be on an earlier page.
+    fn fill_buf(&mut self) -> Result<(), Error> {
+        let updates = self.load_updates_page()?;
+        let (first_update, last_update) = match (updates.first(), updates.last()) {
+            (Some(first_update), Some(last_update)) => (first_update, last_update),
+            _ => {
+                self.done = true;
+                return Ok(());
+            }
+        };
+        self.cursor = Some(last_update.update_time);
+
+        let stored_artists = self.load_stored_rankings(
+            SnapshotEntity::Artists,
+            first_update.update_time,
+            last_update.update_time,
+        )?;
+        let stored_tracks = self.load_stored_rankings(
+            SnapshotEntity::Tracks,
+            first_update.update_time,
+            last_update.update_time,
+        )?;
+        let artist_history = crate::db_util::carry_forward_timeframes_from(
+            &updates,
+            SnapshotEntity::Artists,
+            &HistoryFilter::default(),
+            stored_artists,
+            &mut self.cur_artist_rankings,
+        );
+        let track_history = crate::db_util::carry_forward_timeframes_from(
+            &updates,
+            SnapshotEntity::Tracks,
+            &HistoryFilter::default(),
+            stored_tracks,
+            &mut self.cur_track_rankings,
+        );
+
+        let updates_by_time: HashMap<NaiveDateTime, &StatsUpdate> = updates
+            .iter()
+            .map(|update| (update.update_time, update))
+            .collect();
+        self.write_rankings(SnapshotEntity::Artists, &updates_by_time, &artist_history)?;
+        self.write_rankings(SnapshotEntity::Tracks, &updates_by_time, &track_history)
+    }
+}
+
+impl Read for SnapshotExportReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.buf_pos >= self.buf.len() && !self.done {
+            self.buf.clear();
+            self.buf_pos = 0;
+            self.fill_buf()
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        }
+
+        let remaining = &self.buf[self.buf_pos..];
+        let len = remaining.len().min(out.len());
+        out[..len].copy_from_slice(&remaining[..len]);
+        self.buf_pos += len;
+        Ok(len)
+    }
+}
+
+#[test]
+fn csv_field_escaping() {
+    assert_eq!(escape_csv_field("Radiohead"), "Radiohead");
+    assert_eq!(
+        escape_csv_field("Crosby, Stills & Nash"),
+        "\"Crosby, Stills & Nash\""
+    );
+    assert_eq!(
+        escape_csv_field("The \"Best\" Of"),
+        "\"The \"\"Best\"\" Of\""
+    );
+    assert_eq!(escape_csv_field("line\nbreak"), "\"line\nbreak\"");
+    assert_eq!(escape_csv_field(""), "");
+}
+
+#[test]
+fn rankings_carried_across_pages() {
+    use crate::db_util::{carry_forward_timeframes_from, test_update};
+
+    let updates = vec![
+        test_update(0, 0b111, 0b000),
+        test_update(1, 0b000, 0b000),
+        // The short and long timeframes for the second page come from the first update on the first page
+        test_update(2, 0b010, 0b000),
+        test_update(3, 0b000, 0b000),
+    ];
+    let stored = |update_time: NaiveDateTime, timeframes: TimeFrames<u32>| {
+        let mut stored = HashMap::new();
+        stored.insert(update_time, timeframes);
+        stored
+    };
+    let first_page_stored = stored(
+        updates[0].update_time,
+        TimeFrames {
+            short: vec![1, 2],
+            medium: vec![3],
+            long: vec![4],
+        },
+    );
+    let second_page_stored = stored(
+        updates[2].update_time,
+        TimeFrames {
+            short: Vec::new(),
+            medium: vec![5],
+            long: Vec::new(),
+        },
+    );
+
+    let mut cur_rankings = TimeFrames::default();
+    let mut history = carry_forward_timeframes_from(
+        &updates[..2],
+        SnapshotEntity::Artists,
+        &HistoryFilter::default(),
+        first_page_stored,
+        &mut cur_rankings,
+    );
+    history.extend(carry_forward_timeframes_from(
+        &updates[2..],
+        SnapshotEntity::Artists,
+        &HistoryFilter::default(),
+        second_page_stored,
+        &mut cur_rankings,
+    ));
+
+    let history: Vec<(NaiveDateTime, [Vec<u32>; 3])> = history
+        .into_iter()
+        .map(|(update_time, timeframes)| {
+            (
+                update_time,
+                [timeframes.short, timeframes.medium, timeframes.long],
+            )
+        })
+        .collect();
+    assert_eq!(
+        history,
+        vec![
+            (updates[0].update_time, [vec![1, 2], vec![3], vec![4]]),
+            (updates[1].update_time, [vec![1, 2], vec![3], vec![4]]),
+            (updates[2].update_time, [vec![1, 2], vec![5], vec![4]]),
+            (updates[3].update_time, [vec![1, 2], vec![5], vec![4]]),
+        ]
+    );
+}
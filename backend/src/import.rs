@@ -0,0 +1,413 @@
+//! Importing of the streaming history files included in Spotify's personal data export.  Two formats are supported:
+//! the `StreamingHistory*.json` files from the basic account data export, which only identify tracks by name, and
+//! the `endsong_*.json` files from the extended streaming history export, which include track URIs.
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use hashbrown::{HashMap, HashSet};
+
+use crate::error::Error;
+use crate::models::{HasSpotifyId, TrackPlay, User};
+use crate::DbConn;
+
+/// Plays shorter than this aren't counted as plays, matching Spotify's own definition of a stream
+const MIN_PLAY_DURATION_MS: u64 = 30_000;
+const SPOTIFY_TRACK_URI_PREFIX: &str = "spotify:track:";
+/// Plays with minute-precision times are considered the same as an already-stored play of the same track if they're
+/// within this many seconds of each other
+const MINUTE_PRECISION_MATCH_WINDOW_SECONDS: i64 = 60;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StreamingHistoryEntry {
+    /// UTC time that the play ended, formatted like `2019-05-01 12:34`
+    end_time: String,
+    artist_name: String,
+    track_name: String,
+    ms_played: u64,
+}
+
+#[derive(Deserialize)]
+struct EndsongEntry {
+    ts: DateTime<Utc>,
+    ms_played: u64,
+    spotify_track_uri: Option<String>,
+    master_metadata_track_name: Option<String>,
+    master_metadata_album_artist_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HistoryEntry {
+    Endsong(EndsongEntry),
+    StreamingHistory(StreamingHistoryEntry),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TrackRef {
+    SpotifyId(String),
+    /// Tracks without an ID need to be looked up by name with the search API
+    Name {
+        artist_name: String,
+        track_name: String,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParsedPlay {
+    pub track: TrackRef,
+    pub played_at: NaiveDateTime,
+    /// Set for plays from the basic export, which only records the minute in which a play ended
+    pub minute_precision: bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportSummary {
+    pub plays_in_file: usize,
+    /// Plays that were too short to count
+    pub plays_too_short: usize,
+    /// Plays of things that couldn't be matched to a track on Spotify, such as podcast episodes or local files
+    pub plays_unresolved: usize,
+    /// New plays that were stored.  Plays that had already been stored, either by an earlier import or from the user's
+    /// recently-played history, aren't counted.
+    pub plays_stored: usize,
+}
+
+/// Parses the contents of a streaming history file in either format, dropping plays that are too short to count.
+pub fn parse_streaming_history(data: &str) -> Result<(Vec<ParsedPlay>, ImportSummary), Error> {
+    let entries: Vec<HistoryEntry> = serde_json::from_str(data).map_err(|err| -> Error {
+        info!("Error parsing streaming history file: {:?}", err);
+        Error::BadRequest(format!("Invalid streaming history file: {}", err))
+    })?;
+
+    let mut summary = ImportSummary {
+        plays_in_file: entries.len(),
+        ..ImportSummary::default()
+    };
+    let mut plays = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let (ms_played, played_at, minute_precision, track) = match entry {
+            HistoryEntry::StreamingHistory(entry) => {
+                let played_at = NaiveDateTime::parse_from_str(&entry.end_time, "%Y-%m-%d %H:%M")
+                    .map_err(|_| -> Error {
+                        Error::BadRequest(format!(
+                            "Invalid play time in streaming history file: {}",
+                            entry.end_time
+                        ))
+                    })?;
+                let track = TrackRef::Name {
+                    artist_name: entry.artist_name,
+                    track_name: entry.track_name,
+                };
+                (entry.ms_played, played_at, true, Some(track))
+            }
+            HistoryEntry::Endsong(entry) => {
+                let track_id = entry.spotify_track_uri.as_ref().and_then(|uri| {
+                    if uri.starts_with(SPOTIFY_TRACK_URI_PREFIX) {
+                        Some(uri[SPOTIFY_TRACK_URI_PREFIX.len()..].to_string())
+                    } else {
+                        None
+                    }
+                });
+                let track = match (
+                    track_id,
+                    entry.master_metadata_album_artist_name,
+                    entry.master_metadata_track_name,
+                ) {
+                    (Some(track_id), _, _) => Some(TrackRef::SpotifyId(track_id)),
+                    (None, Some(artist_name), Some(track_name)) => Some(TrackRef::Name {
+                        artist_name,
+                        track_name,
+                    }),
+                    _ => None,
+                };
+                (entry.ms_played, entry.ts.naive_utc(), false, track)
+            }
+        };
+
+        if ms_played < MIN_PLAY_DURATION_MS {
+            summary.plays_too_short += 1;
+            continue;
+        }
+        match track {
+            Some(track) => plays.push(ParsedPlay {
+                track,
+                played_at,
+                minute_precision,
+            }),
+            None => summary.plays_unresolved += 1,
+        }
+    }
+
+    Ok((plays, summary))
+}
+
+/// Plays from the basic export only record the minute they ended in, so they can't be de-duplicated against stored
+/// plays by time alone.  Each of them is matched to at most one stored play of the same track within a minute of it
+/// and dropped if one is found, since it was already stored by an earlier import or from recently-played history.
+/// The rest are moved to a second within their minute that no other play uses, since play times must be unique for
+/// each user and several plays can end in the same minute.
+///
+/// `stored_plays` must include every stored play within a minute of the minute-precision plays.  Returns the plays to
+/// store as `(track ID, play time)` pairs.
+fn dedupe_minute_precision_plays<'a>(
+    plays: Vec<(&'a str, NaiveDateTime, bool)>,
+    stored_plays: &[(String, NaiveDateTime)],
+) -> Vec<(&'a str, NaiveDateTime)> {
+    let mut used_times: HashSet<NaiveDateTime> = stored_plays
+        .iter()
+        .map(|(_, played_at)| *played_at)
+        .collect();
+    used_times.extend(
+        plays
+            .iter()
+            .filter(|(_, _, minute_precision)| !minute_precision)
+            .map(|(_, played_at, _)| *played_at),
+    );
+    let mut unmatched_stored_plays: HashMap<&str, Vec<NaiveDateTime>> = HashMap::new();
+    for (track_id, played_at) in stored_plays {
+        unmatched_stored_plays
+            .entry(track_id.as_str())
+            .or_insert_with(Vec::new)
+            .push(*played_at);
+    }
+
+    plays
+        .into_iter()
+        .filter_map(|(track_id, played_at, minute_precision)| {
+            if !minute_precision {
+                return Some((track_id, played_at));
+            }
+
+            if let Some(stored_times) = unmatched_stored_plays.get_mut(track_id) {
+                let closest_match = stored_times
+                    .iter()
+                    .enumerate()
+                    .map(|(i, stored_time)| (i, (*stored_time - played_at).num_seconds().abs()))
+                    .filter(|(_, distance)| *distance <= MINUTE_PRECISION_MATCH_WINDOW_SECONDS)
+                    .min_by_key(|(_, distance)| *distance);
+                if let Some((i, _)) = closest_match {
+                    stored_times.swap_remove(i);
+                    return None;
+                }
+            }
+
+            let played_at = (0..60)
+                .map(|second| played_at + Duration::seconds(second))
+                .find(|candidate| !used_times.contains(candidate))
+                .unwrap_or(played_at);
+            used_times.insert(played_at);
+            Some((track_id, played_at))
+        })
+        .collect()
+}
+
+/// Returns the time ranges that stored plays need to be loaded for in order to de-duplicate plays at the provided
+/// minute-precision times: one for each time widened by the match window, with overlapping ranges merged.  This keeps
+/// imports from loading every play in between when the file spans years.
+fn get_match_windows(mut times: Vec<NaiveDateTime>) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let match_window = Duration::seconds(MINUTE_PRECISION_MATCH_WINDOW_SECONDS);
+    times.sort_unstable();
+    times.dedup();
+
+    let mut windows: Vec<(NaiveDateTime, NaiveDateTime)> = Vec::new();
+    for time in times {
+        let (start, end) = (time - match_window, time + match_window);
+        match windows.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => windows.push((start, end)),
+        }
+    }
+    windows
+}
+
+/// Stores plays parsed out of a streaming history file as play events for the user, filling in the summary returned
+/// by the parser.  Tracks that are only identified by name are looked up with the Spotify search API.
+pub fn import_streaming_history(
+    conn: &DbConn,
+    user: &User,
+    spotify_access_token: &str,
+    plays: &[ParsedPlay],
+    mut summary: ImportSummary,
+) -> Result<ImportSummary, Error> {
+    // Look up the IDs of all tracks that were only identified by name
+    let mut name_queries: Vec<(&str, &str)> = plays
+        .iter()
+        .filter_map(|play| match &play.track {
+            TrackRef::Name {
+                artist_name,
+                track_name,
+            } => Some((artist_name.as_str(), track_name.as_str())),
+            TrackRef::SpotifyId(_) => None,
+        })
+        .collect();
+    name_queries.sort_unstable();
+    name_queries.dedup();
+    let searched_track_ids: HashMap<(&str, &str), Option<String>> = name_queries
+        .iter()
+        .copied()
+        .zip(crate::spotify_api::search_track_ids(
+            spotify_access_token,
+            &name_queries,
+        )?)
+        .collect();
+    info!(
+        "Looked up {} tracks by name while importing streaming history for user {}",
+        searched_track_ids.len(),
+        user.username
+    );
+
+    let resolved_plays: Vec<(&str, NaiveDateTime, bool)> = plays
+        .iter()
+        .filter_map(|play| {
+            let track_id = match &play.track {
+                TrackRef::SpotifyId(track_id) => Some(track_id.as_str()),
+                TrackRef::Name {
+                    artist_name,
+                    track_name,
+                } => searched_track_ids[&(artist_name.as_str(), track_name.as_str())]
+                    .as_ref()
+                    .map(String::as_str),
+            };
+            track_id.map(|track_id| (track_id, play.played_at, play.minute_precision))
+        })
+        .collect();
+    summary.plays_unresolved += plays.len() - resolved_plays.len();
+
+    let minute_precision_times: Vec<NaiveDateTime> = resolved_plays
+        .iter()
+        .filter(|(_, _, minute_precision)| *minute_precision)
+        .map(|(_, played_at, _)| *played_at)
+        .collect();
+    let mut stored_plays = Vec::new();
+    for (start, end) in get_match_windows(minute_precision_times) {
+        stored_plays.extend(crate::db_util::get_play_events_between(
+            conn, user, start, end,
+        )?);
+    }
+    let resolved_plays = dedupe_minute_precision_plays(resolved_plays, &stored_plays);
+
+    // Fetch track metadata so that we can map tracks to their artists.  Track URIs that Spotify doesn't know about,
+    // such as those of tracks that have since been removed, come back as `null` and are counted as unresolved.
+    let mut track_ids: Vec<&str> = resolved_plays
+        .iter()
+        .map(|(track_id, _)| *track_id)
+        .collect();
+    track_ids.sort_unstable();
+    track_ids.dedup();
+    let artist_ids_by_track_id: HashMap<String, Vec<String>> =
+        crate::spotify_api::fetch_optional_tracks(spotify_access_token, &track_ids)?
+            .into_iter()
+            .flatten()
+            .map(|track| {
+                let artist_ids = track
+                    .artists
+                    .iter()
+                    .map(|artist| artist.id.clone())
+                    .collect();
+                (track.get_spotify_id().to_string(), artist_ids)
+            })
+            .collect();
+
+    let resolved_play_count = resolved_plays.len();
+    let track_plays: Vec<TrackPlay> = resolved_plays
+        .into_iter()
+        .filter_map(|(track_id, played_at)| {
+            Some(TrackPlay {
+                track_spotify_id: track_id.to_string(),
+                artist_spotify_ids: artist_ids_by_track_id.get(track_id)?.clone(),
+                played_at,
+            })
+        })
+        .collect();
+    summary.plays_unresolved += resolved_play_count - track_plays.len();
+
+    summary.plays_stored = crate::db_util::with_transaction(conn, || {
+        crate::db_util::store_play_events(conn, user, &track_plays)
+    })?;
+    Ok(summary)
+}
+
+#[test]
+fn parse_both_streaming_history_formats() {
+    let (plays, summary) = parse_streaming_history(
+        r#"[
+            {"endTime": "2019-05-01 12:34", "artistName": "Artist", "trackName": "Track", "msPlayed": 200000},
+            {"endTime": "2019-05-01 12:40", "artistName": "Artist", "trackName": "Skipped", "msPlayed": 5000}
+        ]"#,
+    )
+    .expect("Error parsing basic streaming history");
+    assert_eq!(
+        plays,
+        vec![ParsedPlay {
+            track: TrackRef::Name {
+                artist_name: "Artist".into(),
+                track_name: "Track".into(),
+            },
+            played_at: NaiveDateTime::parse_from_str("2019-05-01 12:34", "%Y-%m-%d %H:%M").unwrap(),
+            minute_precision: true,
+        }]
+    );
+    assert_eq!(summary.plays_too_short, 1);
+
+    let (plays, summary) = parse_streaming_history(
+        r#"[
+            {"ts": "2019-05-01T12:34:56Z", "ms_played": 200000, "spotify_track_uri": "spotify:track:abc",
+             "master_metadata_track_name": "Track", "master_metadata_album_artist_name": "Artist"},
+            {"ts": "2019-05-01T13:00:00Z", "ms_played": 600000, "spotify_track_uri": null,
+             "master_metadata_track_name": null, "master_metadata_album_artist_name": null}
+        ]"#,
+    )
+    .expect("Error parsing extended streaming history");
+    assert_eq!(plays.len(), 1);
+    assert_eq!(plays[0].track, TrackRef::SpotifyId("abc".into()));
+    assert_eq!(summary.plays_unresolved, 1);
+}
+
+#[test]
+fn minute_precision_play_deduplication() {
+    let time = |time: &str| NaiveDateTime::parse_from_str(time, "%H:%M:%S %Y-%m-%d").unwrap();
+    let stored_plays = vec![
+        // Stored from recently-played history
+        ("a".to_string(), time("12:34:30 2019-05-01")),
+        // Stored by an earlier import of a different file
+        ("b".to_string(), time("12:40:00 2019-05-01")),
+    ];
+    let plays = vec![
+        ("a", time("12:34:00 2019-05-01"), true),
+        // Played again in the same minute
+        ("a", time("12:34:00 2019-05-01"), true),
+        ("b", time("12:39:00 2019-05-01"), true),
+        // A different track ending in the same minute as a stored play
+        ("c", time("12:40:00 2019-05-01"), true),
+        // Plays with exact times are passed through as-is
+        ("d", time("12:50:12 2019-05-01"), false),
+    ];
+
+    assert_eq!(
+        dedupe_minute_precision_plays(plays, &stored_plays),
+        vec![
+            ("a", time("12:34:00 2019-05-01")),
+            ("c", time("12:40:01 2019-05-01")),
+            ("d", time("12:50:12 2019-05-01")),
+        ]
+    );
+}
+
+#[test]
+fn minute_precision_match_windows() {
+    let time = |time: &str| NaiveDateTime::parse_from_str(time, "%H:%M:%S %Y-%m-%d").unwrap();
+    let times = vec![
+        time("12:35:00 2019-05-01"),
+        time("12:34:00 2019-05-01"),
+        time("12:34:00 2019-05-01"),
+        time("08:00:00 2021-01-01"),
+    ];
+
+    assert_eq!(
+        get_match_windows(times),
+        vec![
+            (time("12:33:00 2019-05-01"), time("12:36:00 2019-05-01")),
+            (time("07:59:00 2021-01-01"), time("08:01:00 2021-01-01")),
+        ]
+    );
+}
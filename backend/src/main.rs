@@ -18,6 +18,7 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 extern crate r2d2_redis;
+extern crate rand;
 extern crate rayon;
 extern crate redis;
 #[macro_use]
@@ -28,18 +29,28 @@ extern crate serde;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
+extern crate toml;
 
 use std::sync::Mutex;
 
+use rocket::fairing::AdHoc;
 use rocket_contrib::compression::Compression;
 
+pub mod auth;
 pub mod benchmarking;
 pub mod cache;
 pub mod conf;
 pub mod cors;
 pub mod db_util;
+pub mod error;
+pub mod export;
+pub mod import;
+pub mod metrics;
+#[cfg(test)]
+pub mod mock_spotify;
 pub mod models;
 pub mod routes;
+pub mod scheduler;
 pub mod schema;
 pub mod spotify_api;
 pub mod spotify_token;
@@ -50,8 +61,10 @@ use self::spotify_token::SpotifyTokenData;
 #[database("spotify_homepage")]
 pub struct DbConn(diesel::MysqlConnection);
 
-fn main() {
-    dotenv::dotenv().expect("dotenv file parsing failed");
+/// Builds the application with all routes, catchers, fairings, and managed state attached, ready to be launched or
+/// driven with a local client in tests.
+pub fn build_rocket() -> rocket::Rocket {
+    conf::CONF.export_rocket_database_config();
 
     rocket::ignite()
         .mount(
@@ -66,12 +79,42 @@ fn main() {
                 routes::get_genre_history,
                 routes::populate_tracks_artists_mapping_table,
                 routes::populate_artists_genres_mapping_table,
-                routes::get_genre_stats
+                routes::get_genre_stats,
+                routes::get_scheduler_status,
+                routes::get_metrics,
+                routes::repair_partial_snapshots,
+                routes::compare_users,
+                routes::get_update_timestamps,
+                routes::get_track_stats,
+                routes::get_album_stats,
+                routes::get_stats_diff,
+                routes::export_user_data,
+                routes::import_streaming_history,
+                routes::delete_user_account
             ],
         )
+        .register(catchers![
+            error::bad_request,
+            error::unauthorized,
+            error::not_found,
+            error::internal_error,
+            error::bad_gateway,
+            error::service_unavailable
+        ])
         .attach(DbConn::fairing())
         .attach(cors::CorsFairing)
+        .attach(metrics::RequestMetricsFairing)
         .attach(Compression::fairing())
         .manage(Mutex::new(SpotifyTokenData::new()))
-        .launch();
+        .attach(AdHoc::on_launch("Config Logger", |_| {
+            info!("Effective configuration:\n{}", *conf::CONF)
+        }))
+        .attach(AdHoc::on_launch("Update Scheduler", scheduler::start))
+}
+
+fn main() {
+    // Load and validate the config up front so that all problems with it are reported before anything else happens
+    lazy_static::initialize(&conf::CONF);
+
+    build_rocket().launch();
 }
@@ -0,0 +1,345 @@
+//! Process-wide metrics for events that are worth keeping an eye on in production.  They're exposed in the Prometheus
+//! text format by the `/metrics` route.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+use crate::error::Error;
+use crate::scheduler::UpdateOutcome;
+
+const LATENCY_BUCKET_COUNT: usize = 12;
+/// Upper bounds of the buckets that latencies are counted in, in seconds
+const LATENCY_BUCKETS: [f64; LATENCY_BUCKET_COUNT] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+fn escape_label_value(val: &str) -> String {
+    val.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Formats labels like `{method="GET",route="/"}`, including `extra` after the metric's own labels if provided
+fn format_labels(names: &[&str], values: &[String], extra: Option<(&str, &str)>) -> String {
+    let labels: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, val)| (*name, val.as_str()))
+        .chain(extra)
+        .map(|(name, val)| format!("{}=\"{}\"", name, escape_label_value(val)))
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+/// Counter that's tracked separately for each combination of values for its labels
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    label_names: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    fn new(name: &'static str, help: &'static str, label_names: &'static [&'static str]) -> Self {
+        CounterVec {
+            name,
+            help,
+            label_names,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// `label_values` must be in the same order as the label names the counter was created with
+    pub fn inc_by(&self, label_values: &[&str], count: u64) {
+        debug_assert_eq!(label_values.len(), self.label_names.len());
+        let key = label_values.iter().map(|&val| val.to_owned()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += count;
+    }
+
+    pub fn inc(&self, label_values: &[&str]) {
+        self.inc_by(label_values, 1)
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "counter");
+        for (label_values, count) in self.values.lock().unwrap().iter() {
+            let labels = format_labels(self.label_names, label_values, None);
+            writeln!(out, "{}{} {}", self.name, labels, count).unwrap();
+        }
+    }
+}
+
+struct HistogramData {
+    /// Number of observations that fell into each of `LATENCY_BUCKETS`, not including those in lower buckets
+    bucket_counts: [u64; LATENCY_BUCKET_COUNT],
+    sum: f64,
+    count: u64,
+}
+
+/// Latency histogram using `LATENCY_BUCKETS` that's tracked separately for each combination of values for its labels
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    label_names: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, HistogramData>>,
+}
+
+impl HistogramVec {
+    fn new(name: &'static str, help: &'static str, label_names: &'static [&'static str]) -> Self {
+        HistogramVec {
+            name,
+            help,
+            label_names,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// `label_values` must be in the same order as the label names the histogram was created with
+    pub fn observe(&self, label_values: &[&str], duration: Duration) {
+        debug_assert_eq!(label_values.len(), self.label_names.len());
+        let secs = duration.as_secs_f64();
+        let key = label_values.iter().map(|&val| val.to_owned()).collect();
+
+        let values = &mut *self.values.lock().unwrap();
+        let data = values.entry(key).or_insert_with(|| HistogramData {
+            bucket_counts: [0; LATENCY_BUCKET_COUNT],
+            sum: 0.,
+            count: 0,
+        });
+        if let Some(bucket_ix) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            data.bucket_counts[bucket_ix] += 1;
+        }
+        data.sum += secs;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "histogram");
+        for (label_values, data) in self.values.lock().unwrap().iter() {
+            // Prometheus buckets are cumulative
+            let mut cumulative_count = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(data.bucket_counts.iter()) {
+                cumulative_count += count;
+                let labels = format_labels(
+                    self.label_names,
+                    label_values,
+                    Some(("le", &bound.to_string())),
+                );
+                writeln!(out, "{}_bucket{} {}", self.name, labels, cumulative_count).unwrap();
+            }
+            let labels = format_labels(self.label_names, label_values, Some(("le", "+Inf")));
+            writeln!(out, "{}_bucket{} {}", self.name, labels, data.count).unwrap();
+
+            let labels = format_labels(self.label_names, label_values, None);
+            writeln!(out, "{}_sum{} {}", self.name, labels, data.sum).unwrap();
+            writeln!(out, "{}_count{} {}", self.name, labels, data.count).unwrap();
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: CounterVec = CounterVec::new(
+        "http_requests_total",
+        "Number of HTTP requests handled, by route and response status",
+        &["method", "route", "status"],
+    );
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = HistogramVec::new(
+        "http_request_duration_seconds",
+        "Time taken to handle HTTP requests, by route",
+        &["method", "route"],
+    );
+    pub static ref SPOTIFY_API_REQUESTS: CounterVec = CounterVec::new(
+        "spotify_api_requests_total",
+        "Number of requests made to the Spotify API, by endpoint and response status or `error` if no response was received",
+        &["endpoint", "status"],
+    );
+    pub static ref SPOTIFY_RETRIES: CounterVec = CounterVec::new(
+        "spotify_retries_total",
+        "Number of Spotify API requests that were retried after a 429 or 5xx response, by endpoint",
+        &["endpoint"],
+    );
+    pub static ref SPOTIFY_RETRIES_EXHAUSTED: CounterVec = CounterVec::new(
+        "spotify_retries_exhausted_total",
+        "Number of Spotify API requests that were given up on after exhausting the retry budget, by endpoint",
+        &["endpoint"],
+    );
+    pub static ref SPOTIFY_API_REQUEST_DURATION: HistogramVec = HistogramVec::new(
+        "spotify_api_request_duration_seconds",
+        "Time taken by requests to the Spotify API, by endpoint",
+        &["endpoint"],
+    );
+    pub static ref METADATA_CACHE_LOOKUPS: CounterVec = CounterVec::new(
+        "metadata_cache_lookups_total",
+        "Number of items looked up in the metadata cache, by cache and whether they were a fresh hit, a stale hit, or a miss",
+        &["cache", "result"],
+    );
+    pub static ref TOKEN_REFRESHES: CounterVec = CounterVec::new(
+        "spotify_token_refreshes_total",
+        "Number of Spotify access token refreshes, by whether they were for the app or a user and whether they succeeded",
+        &["kind", "result"],
+    );
+    pub static ref USER_UPDATES: CounterVec = CounterVec::new(
+        "user_updates_total",
+        "Number of user stats updates run by the update scheduler, by outcome",
+        &["outcome"],
+    );
+}
+
+/// Records whether a token refresh succeeded.  `kind` is `app` for the client credentials token and `user` for users'
+/// tokens.
+pub fn record_token_refresh<T>(kind: &str, res: &Result<T, Error>) {
+    let result = if res.is_ok() { "success" } else { "failure" };
+    TOKEN_REFRESHES.inc(&[kind, result]);
+}
+
+pub fn record_update_outcome(res: &Result<UpdateOutcome, Error>) {
+    let outcome = match res {
+        Ok(UpdateOutcome::Updated) => "updated",
+        Ok(UpdateOutcome::NotDue(_)) => "not_due",
+        Ok(UpdateOutcome::TokenRefreshFailed(_)) => "token_refresh_failed",
+        Ok(UpdateOutcome::Disabled) => "disabled",
+        Err(_) => "error",
+    };
+    USER_UPDATES.inc(&[outcome]);
+}
+
+/// Usage of a connection pool at the time metrics are rendered
+pub struct PoolUsage {
+    pub name: &'static str,
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+}
+
+/// Renders all metrics in the Prometheus text exposition format
+pub fn render(pools: &[PoolUsage], scheduler_queue_depth: usize) -> String {
+    let mut out = String::new();
+
+    HTTP_REQUESTS.render(&mut out);
+    HTTP_REQUEST_DURATION.render(&mut out);
+    SPOTIFY_API_REQUESTS.render(&mut out);
+    SPOTIFY_RETRIES.render(&mut out);
+    SPOTIFY_RETRIES_EXHAUSTED.render(&mut out);
+    SPOTIFY_API_REQUEST_DURATION.render(&mut out);
+    METADATA_CACHE_LOOKUPS.render(&mut out);
+    TOKEN_REFRESHES.render(&mut out);
+    USER_UPDATES.render(&mut out);
+
+    write_header(
+        &mut out,
+        "connection_pool_connections",
+        "Number of open connections in each connection pool, by whether they're idle or in use",
+        "gauge",
+    );
+    for pool in pools {
+        for &(state, count) in &[
+            ("idle", pool.idle_connections),
+            ("active", pool.connections - pool.idle_connections),
+        ] {
+            writeln!(
+                out,
+                "connection_pool_connections{{pool=\"{}\",state=\"{}\"}} {}",
+                pool.name, state, count
+            )
+            .unwrap();
+        }
+    }
+    write_header(
+        &mut out,
+        "connection_pool_max_size",
+        "Maximum number of connections in each connection pool",
+        "gauge",
+    );
+    for pool in pools {
+        writeln!(
+            out,
+            "connection_pool_max_size{{pool=\"{}\"}} {}",
+            pool.name, pool.max_size
+        )
+        .unwrap();
+    }
+
+    write_header(
+        &mut out,
+        "scheduler_queue_depth",
+        "Number of users from the current update scheduler run that haven't finished updating yet",
+        "gauge",
+    );
+    writeln!(out, "scheduler_queue_depth {}", scheduler_queue_depth).unwrap();
+
+    out
+}
+
+/// Stored in each request's local cache so that the time taken to handle it can be computed
+struct RequestStartTime(Instant);
+
+/// Records the count and latency of all requests, labelled with the route that handled them
+pub struct RequestMetricsFairing;
+
+impl Fairing for RequestMetricsFairing {
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStartTime(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let RequestStartTime(start_time) = request.local_cache(|| RequestStartTime(Instant::now()));
+        // The route's URI template is used rather than the request's actual path to keep the number of labels bounded
+        let route = request
+            .route()
+            .map(|route| route.uri.path())
+            .unwrap_or("unmatched");
+        let method = request.method().as_str();
+
+        HTTP_REQUESTS.inc(&[method, route, &response.status().code.to_string()]);
+        HTTP_REQUEST_DURATION.observe(&[method, route], start_time.elapsed());
+    }
+
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Metrics Fairing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+}
+
+#[test]
+fn prometheus_rendering() {
+    let counter = CounterVec::new("test_total", "Test counter", &["kind"]);
+    counter.inc(&["a"]);
+    counter.inc_by(&["b\""], 3);
+    counter.inc(&["a"]);
+    let mut out = String::new();
+    counter.render(&mut out);
+    assert_eq!(
+        out,
+        "# HELP test_total Test counter\n# TYPE test_total counter\ntest_total{kind=\"a\"} 2\ntest_total{kind=\"b\\\"\"} 3\n"
+    );
+
+    let histogram = HistogramVec::new("test_seconds", "Test histogram", &[]);
+    histogram.observe(&[], Duration::from_millis(20));
+    histogram.observe(&[], Duration::from_millis(200));
+    histogram.observe(&[], Duration::from_secs(60));
+    let mut out = String::new();
+    histogram.render(&mut out);
+    let lines: Vec<&str> = out.lines().collect();
+    assert!(lines.contains(&"test_seconds_bucket{le=\"0.01\"} 0"));
+    assert!(lines.contains(&"test_seconds_bucket{le=\"0.025\"} 1"));
+    assert!(lines.contains(&"test_seconds_bucket{le=\"0.25\"} 2"));
+    assert!(lines.contains(&"test_seconds_bucket{le=\"30\"} 2"));
+    assert!(lines.contains(&"test_seconds_bucket{le=\"+Inf\"} 3"));
+    assert!(lines.contains(&"test_seconds_count 3"));
+}
@@ -0,0 +1,298 @@
+//! Mock of the parts of the Spotify API and accounts service that the backend talks to.  It serves canned responses
+//! for a single user so that the full flow from authorization through serving stats can be tested end-to-end without
+//! hitting Spotify.  Point `SPOTIFY_API_BASE_URL` and `SPOTIFY_ACCOUNTS_BASE_URL` at it before `CONF` is first used.
+
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use rocket::config::{Config, Environment, LoggingLevel};
+use rocket::http::{Header, Status};
+use rocket::local::Client;
+use rocket::request::LenientForm;
+use rocket_contrib::json::{Json, JsonValue};
+
+use crate::conf::CONF;
+use crate::models::{Album, Artist, Followers, Image, Track, UserProfile};
+
+pub const MOCK_USER_SPOTIFY_ID: &str = "mock-user";
+pub const MOCK_USER_ACCESS_TOKEN: &str = "mock-user-access-token";
+const MOCK_TOKEN_EXPIRY_SECONDS: usize = 60 * 60;
+/// Base URL of the `href`s in artists and tracks.  Nothing follows them, so they point at the real API instead of the
+/// mock server, which lets unit tests use these fixtures without initializing `CONF`.
+const MOCK_HREF_BASE_URL: &str = "https://api.spotify.com/v1";
+
+pub fn mock_artist(id: &str, name: &str, genres: &[&str]) -> Artist {
+    Artist {
+        followers: Some(Followers {
+            href: None,
+            total: 1000,
+        }),
+        genres: Some(genres.iter().map(|genre| genre.to_string()).collect()),
+        href: format!("{}/artists/{}", MOCK_HREF_BASE_URL, id),
+        id: id.into(),
+        images: Some(Vec::new()),
+        name: name.into(),
+        popularity: Some(50),
+        uri: format!("spotify:artist:{}", id),
+    }
+}
+
+/// The user's top artists, in ranked order.  They're the same for every timeframe, so `time_range` is ignored.
+fn mock_artists() -> Vec<Artist> {
+    vec![
+        mock_artist("mock-artist-1", "Mock Artist 1", &["mock rock", "mock pop"]),
+        mock_artist("mock-artist-2", "Mock Artist 2", &["mock pop"]),
+        mock_artist("mock-artist-3", "Mock Artist 3", &["mock jazz"]),
+    ]
+}
+
+pub fn mock_track(id: &str, name: &str, artist: Artist) -> Track {
+    let album_id = format!("{}-album", id);
+
+    Track {
+        album: Album {
+            album_group: None,
+            album_type: "album".into(),
+            artists: vec![artist.clone()],
+            available_markets: Vec::new(),
+            href: format!("{}/albums/{}", MOCK_HREF_BASE_URL, album_id),
+            images: vec![Image {
+                height: Some(640),
+                url: "https://example.com/album.jpg".into(),
+                width: Some(640),
+            }],
+            name: format!("{} Album", name),
+            release_date: "2020-01-01".into(),
+            release_date_precision: "day".into(),
+            uri: format!("spotify:album:{}", album_id),
+            id: album_id,
+        },
+        artists: vec![artist],
+        available_markets: Vec::new(),
+        disc_number: 1,
+        duration_ms: 180_000,
+        explicit: false,
+        href: Some(format!("{}/tracks/{}", MOCK_HREF_BASE_URL, id)),
+        id: id.into(),
+        is_playable: None,
+        name: name.into(),
+        popularity: 50,
+        preview_url: None,
+        track_number: 1,
+        uri: format!("spotify:track:{}", id),
+    }
+}
+
+/// The user's top tracks, in ranked order.  They're the same for every timeframe.
+fn mock_tracks() -> Vec<Track> {
+    mock_artists()
+        .into_iter()
+        .enumerate()
+        .map(|(i, artist)| {
+            mock_track(
+                &format!("mock-track-{}", i + 1),
+                &format!("Mock Track {}", i + 1),
+                artist,
+            )
+        })
+        .collect()
+}
+
+fn get_page<T>(items: Vec<T>, limit: usize, offset: usize) -> Vec<T> {
+    items.into_iter().skip(offset).take(limit).collect()
+}
+
+/// Returns the items with the provided comma-separated IDs, in the same order as the IDs
+fn get_by_ids<T: Clone>(items: &[T], ids: &str, get_id: fn(&T) -> &str) -> Vec<T> {
+    ids.split(',')
+        .filter_map(|id| items.iter().find(|item| get_id(item) == id).cloned())
+        .collect()
+}
+
+#[derive(FromForm)]
+struct TokenRequest {
+    grant_type: String,
+}
+
+#[post("/api/token", data = "<req>")]
+fn token(req: LenientForm<TokenRequest>) -> JsonValue {
+    match req.grant_type.as_str() {
+        "client_credentials" => json!({
+            "access_token": "mock-app-access-token",
+            "token_type": "Bearer",
+            "expires_in": MOCK_TOKEN_EXPIRY_SECONDS,
+        }),
+        // `authorization_code` and `refresh_token`
+        _ => json!({
+            "access_token": MOCK_USER_ACCESS_TOKEN,
+            "token_type": "Bearer",
+            "scope": "user-read-recently-played user-top-read user-follow-read",
+            "expires_in": MOCK_TOKEN_EXPIRY_SECONDS,
+            "refresh_token": "mock-user-refresh-token",
+        }),
+    }
+}
+
+#[get("/me")]
+fn profile() -> Json<UserProfile> {
+    Json(UserProfile {
+        display_name: "Mock User".into(),
+        followers: Followers {
+            href: None,
+            total: 0,
+        },
+        href: format!(
+            "{}/users/{}",
+            CONF.spotify_api_base_url, MOCK_USER_SPOTIFY_ID
+        ),
+        images: Vec::new(),
+        id: MOCK_USER_SPOTIFY_ID.into(),
+        uri: format!("spotify:user:{}", MOCK_USER_SPOTIFY_ID),
+    })
+}
+
+#[get("/me/top/artists?<limit>&<offset>")]
+fn top_artists(limit: usize, offset: usize) -> JsonValue {
+    json!({ "items": get_page(mock_artists(), limit, offset) })
+}
+
+#[get("/me/top/tracks?<limit>&<offset>")]
+fn top_tracks(limit: usize, offset: usize) -> JsonValue {
+    json!({ "items": get_page(mock_tracks(), limit, offset) })
+}
+
+#[get("/artists?<ids>")]
+fn batch_artists(ids: String) -> JsonValue {
+    json!({ "artists": get_by_ids(&mock_artists(), &ids, |artist| &artist.id) })
+}
+
+#[get("/tracks?<ids>")]
+fn batch_tracks(ids: String) -> JsonValue {
+    json!({ "tracks": get_by_ids(&mock_tracks(), &ids, |track| &track.id) })
+}
+
+#[get("/me/player/recently-played")]
+fn recently_played() -> JsonValue {
+    json!({ "items": [], "next": null, "cursors": null })
+}
+
+#[get("/search")]
+fn search() -> JsonValue {
+    json!({ "tracks": { "items": [] } })
+}
+
+/// Starts the mock server on a free local port in a background thread.  Returns its base URL once it's accepting
+/// connections; the Spotify API is served under `/v1` and the accounts service at the root.
+pub fn start() -> String {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("Failed to find a free port for the mock Spotify server")
+        .port();
+    let config = Config::build(Environment::Development)
+        .address("127.0.0.1")
+        .port(port)
+        .log_level(LoggingLevel::Critical)
+        .finalize()
+        .expect("Invalid mock Spotify server config");
+
+    thread::spawn(move || {
+        let err = rocket::custom(config)
+            .mount("/", routes![token])
+            .mount(
+                "/v1",
+                routes![
+                    profile,
+                    top_artists,
+                    top_tracks,
+                    batch_artists,
+                    batch_tracks,
+                    recently_played,
+                    search
+                ],
+            )
+            .launch();
+        panic!("Mock Spotify server failed to launch: {}", err);
+    });
+
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return format!("http://127.0.0.1:{}", port);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("Mock Spotify server didn't start listening");
+}
+
+/// Runs through authorizing a new user, which stores their first snapshot, and then fetching their stats.  This needs
+/// the MySQL database configured in `.env`, so it's ignored by default.  `CONF` is process-global and the mock server's
+/// URLs must be set before anything else initializes it, so run it in a test process of its own with `just test-e2e`.
+#[test]
+#[ignore]
+fn oauth_to_stats_end_to_end() {
+    let mock_url = start();
+    std::env::set_var("SPOTIFY_API_BASE_URL", format!("{}/v1", mock_url));
+    std::env::set_var("SPOTIFY_ACCOUNTS_BASE_URL", &mock_url);
+    std::env::set_var("UPDATE_SCHEDULER_ENABLED", "false");
+    std::env::set_var("CACHE_BACKEND", "memory");
+    assert_eq!(
+        CONF.spotify_accounts_base_url, mock_url,
+        "`CONF` was initialized before the mock Spotify server was started"
+    );
+
+    let client = Client::new(crate::build_rocket()).expect("Invalid Rocket instance");
+    let stats_url = format!("/stats/{}", MOCK_USER_SPOTIFY_ID);
+    let delete_user = || {
+        client
+            .delete(stats_url.as_str())
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", MOCK_USER_ACCESS_TOKEN),
+            ))
+            .dispatch()
+            .status()
+    };
+    // Clean up after any previous run so that the user is created from scratch
+    delete_user();
+
+    let res = client.get("/oauth_cb?code=mock-code").dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+    assert_eq!(
+        res.headers().get_one("Location"),
+        Some(format!("{}/stats/{}", CONF.website_url, MOCK_USER_SPOTIFY_ID).as_str())
+    );
+
+    let mut res = client.get(stats_url.as_str()).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let stats: serde_json::Value =
+        serde_json::from_str(&res.body_string().expect("Empty stats response"))
+            .expect("Invalid stats response");
+    for timeframe in &["short", "medium", "long"] {
+        let artist_ids: Vec<&str> = stats["artists"][timeframe]
+            .as_array()
+            .expect("Missing artists for timeframe")
+            .iter()
+            .map(|artist| artist["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            artist_ids,
+            vec!["mock-artist-1", "mock-artist-2", "mock-artist-3"]
+        );
+        let track_ids: Vec<&str> = stats["tracks"][timeframe]
+            .as_array()
+            .expect("Missing tracks for timeframe")
+            .iter()
+            .map(|track| track["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            track_ids,
+            vec!["mock-track-1", "mock-track-2", "mock-track-3"]
+        );
+    }
+
+    assert_eq!(delete_user(), Status::Ok);
+    assert_eq!(
+        client.get(stats_url.as_str()).dispatch().status(),
+        Status::NotFound
+    );
+}
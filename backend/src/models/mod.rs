@@ -2,14 +2,14 @@ use std::default::Default;
 use std::fmt::Debug;
 use std::vec;
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use hashbrown::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::schema::{
-    artist_rank_snapshots, artists_genres, spotify_items, track_rank_snapshots, tracks_artists,
-    users,
+    artist_rank_snapshots, artist_stats_history, artists_genres, play_events, spotify_items,
+    stats_updates, track_rank_snapshots, track_stats_history, tracks_artists, users,
 };
 
 #[derive(Insertable)]
@@ -21,6 +21,7 @@ pub struct NewUser {
     pub username: String,
     pub token: String,
     pub refresh_token: String,
+    pub access_token_expires_at: Option<NaiveDateTime>,
 }
 
 #[derive(Serialize, Queryable, Clone, Debug)]
@@ -32,6 +33,29 @@ pub struct User {
     pub username: String,
     pub token: String,
     pub refresh_token: String,
+    /// Number of consecutive times that refreshing the user's access token has failed
+    pub refresh_failure_count: u32,
+    pub last_refresh_error: Option<String>,
+    /// Disabled users have revoked the app's access (or their token is otherwise unusable) and aren't updated
+    pub disabled: bool,
+    pub access_token_expires_at: Option<NaiveDateTime>,
+}
+
+/// Tokens that expire within this many seconds are treated as already expired to avoid using them right as they expire
+const USER_TOKEN_EXPIRY_MARGIN_SECONDS: i64 = 60;
+
+impl User {
+    /// Returns `true` if the user's stored access token has expired and needs to be refreshed before use.  Tokens
+    /// without a known expiry are assumed to be expired.
+    pub fn access_token_expired(&self) -> bool {
+        match self.access_token_expires_at {
+            Some(expires_at) => {
+                Utc::now().naive_utc() + chrono::Duration::seconds(USER_TOKEN_EXPIRY_MARGIN_SECONDS)
+                    >= expires_at
+            }
+            None => true,
+        }
+    }
 }
 
 #[derive(Serialize, Insertable, Associations)]
@@ -56,6 +80,52 @@ pub struct NewArtistHistoryEntry {
     pub ranking: u16,
 }
 
+#[derive(Insertable)]
+#[table_name = "stats_updates"]
+pub struct NewStatsUpdate {
+    pub user_id: i64,
+    pub update_time: NaiveDateTime,
+    pub artist_timeframes: u8,
+    pub track_timeframes: u8,
+}
+
+/// Record of a single update for a user.  The `*_timeframes` fields are bitmasks of the timeframe IDs for which
+/// rankings were stored in the corresponding snapshot table; timeframes that weren't stored were unchanged since the
+/// previous update.
+#[derive(Queryable, Clone, Debug)]
+pub struct StatsUpdate {
+    pub id: i64,
+    pub user_id: i64,
+    pub update_time: NaiveDateTime,
+    pub artist_timeframes: u8,
+    pub track_timeframes: u8,
+}
+
+#[derive(Insertable)]
+#[table_name = "artist_stats_history"]
+pub struct NewArtistStatsHistoryEntry {
+    pub mapped_spotify_id: i32,
+    pub update_time: NaiveDateTime,
+    pub followers: u64,
+    pub popularity: u64,
+}
+
+#[derive(Insertable)]
+#[table_name = "track_stats_history"]
+pub struct NewTrackStatsHistoryEntry {
+    pub mapped_spotify_id: i32,
+    pub update_time: NaiveDateTime,
+    pub popularity: u64,
+    pub playcount: Option<u64>,
+}
+
+#[derive(Serialize, Queryable, Debug)]
+pub struct ArtistStatsHistoryEntry {
+    pub update_time: NaiveDateTime,
+    pub followers: u64,
+    pub popularity: u64,
+}
+
 #[derive(Serialize, Associations, Debug, Queryable)]
 #[table_name = "spotify_items"]
 pub struct SpotifyIdMapping {
@@ -83,6 +153,22 @@ pub struct ArtistGenrePair {
     pub genre: String,
 }
 
+#[derive(Insertable)]
+#[table_name = "play_events"]
+pub struct NewPlayEvent {
+    pub user_id: i64,
+    pub mapped_spotify_id: i32,
+    pub played_at: NaiveDateTime,
+}
+
+/// A single play of a track by a user, from any source, to be stored as a play event
+#[derive(Clone, Debug)]
+pub struct TrackPlay {
+    pub track_spotify_id: String,
+    pub artist_spotify_ids: Vec<String>,
+    pub played_at: NaiveDateTime,
+}
+
 #[derive(Serialize)]
 pub struct TimeFrames<T: Serialize> {
     pub short: Vec<T>,
@@ -113,6 +199,15 @@ impl<T: Serialize> TimeFrames<T> {
         *collection = items;
     }
 
+    pub fn get_by_id_mut(&mut self, timeframe_id: u8) -> &mut Vec<T> {
+        match timeframe_id {
+            0 => &mut self.short,
+            1 => &mut self.medium,
+            2 => &mut self.long,
+            _ => panic!("Invalid timeframe id passed to `TimeFrames::get_by_id_mut`"),
+        }
+    }
+
     pub fn add_item_by_id(&mut self, timeframe_id: u8, item: T) {
         let collection = match timeframe_id {
             0 => &mut self.short,
@@ -263,6 +358,16 @@ pub struct Album {
     pub uri: String,
 }
 
+#[derive(Clone, Deserialize, Debug)]
+pub struct TrackSearchResults {
+    pub items: Vec<Track>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct TrackSearchResponse {
+    pub tracks: TrackSearchResults,
+}
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct TopTracksResponse {
     pub items: Vec<Track>,
@@ -277,7 +382,7 @@ pub struct StatsHistoryQueryResItem {
 }
 
 #[derive(Queryable)]
-pub struct ArtistRankHistoryResItem {
+pub struct RankHistoryResItem {
     pub update_time: NaiveDateTime,
     pub ranking: u16,
     pub timeframe: u8,
@@ -301,6 +406,33 @@ pub struct Track {
     pub uri: String,
 }
 
+/// Track as returned from the recently-played endpoint.  We only need its ID and artists, and local files are
+/// returned without an ID.
+#[derive(Clone, Deserialize, Debug)]
+pub struct PlayedTrack {
+    pub id: Option<String>,
+    pub artists: Vec<Artist>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct PlayHistoryItem {
+    pub track: PlayedTrack,
+    pub played_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct RecentlyPlayedCursors {
+    pub after: Option<String>,
+    pub before: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct RecentlyPlayedResponse {
+    pub items: Vec<PlayHistoryItem>,
+    pub next: Option<String>,
+    pub cursors: Option<RecentlyPlayedCursors>,
+}
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct TopArtistsResponse {
     pub items: Vec<Artist>,
@@ -364,27 +496,34 @@ pub enum SpotifyResponse<T: std::fmt::Debug + Clone> {
 
 impl<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone> std::ops::Try for SpotifyResponse<T> {
     type Ok = T;
-    type Error = String;
+    type Error = crate::error::Error;
 
-    fn into_result(self) -> Result<Self::Ok, String> {
+    fn into_result(self) -> Result<Self::Ok, crate::error::Error> {
         match self {
             SpotifyResponse::Success(val) => Ok(val),
             SpotifyResponse::Error(err) => {
                 error!("Error fetching data from Spotify API: {:?}", err);
 
-                Err(err
-                    .error
-                    .message
-                    .unwrap_or_else(|| -> String { "No error message supplied".into() }))
+                Err(crate::error::Error::spotify_api(
+                    err.error.status.map(|status| status as u16),
+                    err.error
+                        .message
+                        .unwrap_or_else(|| -> String { "No error message supplied".into() }),
+                ))
             }
         }
     }
 
-    fn from_error(err_msg: String) -> Self {
+    fn from_error(err: crate::error::Error) -> Self {
+        let status = match &err {
+            crate::error::Error::SpotifyApi { status, .. } => status.map(i32::from),
+            _ => None,
+        };
+
         SpotifyResponse::Error(SpotifyError {
             error: SpotifyErrorInner {
-                status: None,
-                message: Some(err_msg),
+                status,
+                message: Some(err.to_string()),
                 other: HashMap::new(),
             },
             other: HashMap::new(),
@@ -398,7 +537,8 @@ impl<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone> std::ops::Try for S
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct SpotifyBatchTracksResponse {
-    pub tracks: Vec<Track>,
+    /// Spotify returns `null` in place of IDs that don't belong to a track
+    pub tracks: Vec<Option<Track>>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -406,6 +546,15 @@ pub struct AccessTokenResponse {
     pub access_token: String,
     pub token_type: String,
     pub expires_in: usize,
+    /// Only included when refreshing user tokens, and only when Spotify decides to rotate the refresh token
+    pub refresh_token: Option<String>,
+}
+
+/// Error body returned by the Spotify accounts service, as described by the OAuth 2.0 spec
+#[derive(Deserialize, Clone, Debug)]
+pub struct OAuthErrorResponse {
+    pub error: String,
+    pub error_description: Option<String>,
 }
 
 pub trait HasSpotifyId {
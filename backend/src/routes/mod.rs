@@ -1,36 +1,113 @@
 use std::io::Read;
 use std::sync::Mutex;
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel::{self, prelude::*};
 use hashbrown::HashMap;
-use rocket::http::{RawStr, Status};
-use rocket::response::status;
-use rocket::{response::Redirect, State};
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::request::{Form, FromFormValue};
+use rocket::response::{status, Content, Stream};
+use rocket::{response::Redirect, Data, State};
 use rocket_contrib::json::Json;
 
+use crate::auth::SpotifyUserAuth;
 use crate::benchmarking::{mark, start};
-use crate::conf::CONF;
-use crate::db_util;
-use crate::models::{Artist, NewUser, OAuthTokenResponse, StatsSnapshot, TimeFrames, Track, User};
-use crate::DbConn;
+use crate::conf::{CacheBackend, CONF};
+use crate::db_util::{self, AccountDeletionReport, HistoryFilter, SnapshotEntity};
+use crate::error::Error;
+use crate::export::{ExportFormat, SnapshotExportReader};
+use crate::import::ImportSummary;
+use crate::metrics::PoolUsage;
+use crate::models::{
+    Album, Artist, ArtistStatsHistoryEntry, NewUser, OAuthTokenResponse, StatsSnapshot,
+    StatsUpdate, TimeFrames, Track, User,
+};
+use crate::scheduler::{SchedulerStatus, UpdateOutcome};
+use crate::stats::{AlbumScores, RankingsDiff, TimeframeComparison};
 use crate::SpotifyTokenData;
+use crate::{DbConn, DbConnPool};
 
-const SPOTIFY_TOKEN_FETCH_URL: &str = "https://accounts.spotify.com/api/token";
+/// Maximum size of an uploaded streaming history file.  Spotify splits exports into files of at most ~10k plays.
+const MAX_IMPORT_FILE_SIZE: u64 = 64 * 1024 * 1024;
 
 #[get("/")]
 pub fn index() -> &'static str {
     "Application successfully started!"
 }
 
-/// Retrieves the current top tracks and artist for the current user
-#[get("/stats/<username>")]
+/// A point in time provided as a query parameter, either as a UNIX timestamp in seconds or as an ISO 8601 datetime
+/// like `2020-06-01T12:00:00` (UTC).
+#[derive(Clone, Copy, Debug)]
+pub struct TimestampParam(pub NaiveDateTime);
+
+impl<'v> FromFormValue<'v> for TimestampParam {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        let decoded = form_value.url_decode().map_err(|_| form_value)?;
+
+        if let Ok(unix_seconds) = decoded.parse::<i64>() {
+            return NaiveDateTime::from_timestamp_opt(unix_seconds, 0)
+                .map(TimestampParam)
+                .ok_or(form_value);
+        }
+        if let Ok(dt) = DateTime::parse_from_rfc3339(&decoded) {
+            return Ok(TimestampParam(dt.with_timezone(&Utc).naive_utc()));
+        }
+        decoded
+            .parse::<NaiveDateTime>()
+            .map(TimestampParam)
+            .map_err(|_| form_value)
+    }
+}
+
+/// A timeframe provided as a query parameter, either by name (`short`, `medium`, or `long`) or by ID
+#[derive(Clone, Copy, Debug)]
+pub struct TimeframeParam(pub u8);
+
+impl<'v> FromFormValue<'v> for TimeframeParam {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.as_str() {
+            "short" | "0" => Ok(TimeframeParam(0)),
+            "medium" | "1" => Ok(TimeframeParam(1)),
+            "long" | "2" => Ok(TimeframeParam(2)),
+            _ => Err(form_value),
+        }
+    }
+}
+
+/// Query parameters accepted by all history routes for restricting the returned updates
+#[derive(FromForm)]
+pub struct HistoryFilterParams {
+    pub from: Option<TimestampParam>,
+    pub to: Option<TimestampParam>,
+    pub timeframe: Option<TimeframeParam>,
+}
+
+impl HistoryFilterParams {
+    pub fn into_filter(self) -> HistoryFilter {
+        HistoryFilter {
+            from: self.from.map(|TimestampParam(from)| from),
+            to: self.to.map(|TimestampParam(to)| to),
+            timeframe: self
+                .timeframe
+                .map(|TimeframeParam(timeframe_id)| timeframe_id),
+        }
+    }
+}
+
+/// Retrieves the top tracks and artist for the current user.  If `at` is provided, the stats from the update closest to
+/// that time are returned instead of the current ones.
+#[get("/stats/<username>?<at>")]
 pub fn get_current_stats(
     conn: DbConn,
     conn2: DbConn,
     username: String,
+    at: Option<TimestampParam>,
     token_data: State<Mutex<SpotifyTokenData>>,
-) -> Result<Option<Json<StatsSnapshot>>, String> {
+) -> Result<Option<Json<StatsSnapshot>>, Error> {
     start();
     let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
         Some(user) => user,
@@ -46,28 +123,222 @@ pub fn get_current_stats(
     }?;
     mark("Got spotify access token");
 
+    let snapshot = match get_stats_snapshot(
+        &user,
+        conn,
+        conn2,
+        &spotify_access_token,
+        at.map(|TimestampParam(at)| at),
+    )? {
+        Some(snapshot) => snapshot,
+        None => return Ok(None),
+    };
+
+    Ok(Some(Json(snapshot)))
+}
+
+/// Lists the times of all of the updates that have been stored for the user
+#[get("/stats/<username>/updates")]
+pub fn get_update_timestamps(
+    conn: DbConn,
+    username: String,
+) -> Result<Option<Json<Vec<NaiveDateTime>>>, Error> {
+    let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        }
+    };
+
+    let update_times = db_util::get_stats_updates(&conn, &user)?
+        .into_iter()
+        .map(|update| update.update_time)
+        .collect();
+    Ok(Some(Json(update_times)))
+}
+
+/// Fetches the user's top artists and tracks for all timeframes as of the update closest to `at`, or as of their last
+/// update if `at` isn't provided.
+fn get_stats_snapshot(
+    user: &User,
+    conn: DbConn,
+    conn2: DbConn,
+    spotify_access_token: &str,
+    at: Option<NaiveDateTime>,
+) -> Result<Option<StatsSnapshot>, Error> {
+    let stats_updates = db_util::get_stats_updates(&conn, user)?;
+    let update_time = match at {
+        Some(at) => match db_util::get_closest_update_time(&stats_updates, at) {
+            Some(update_time) => update_time,
+            None => return Ok(None),
+        },
+        None => user.last_update_time,
+    };
+
+    let snapshot = load_stats_snapshots(
+        conn,
+        conn2,
+        spotify_access_token,
+        &[(user, &stats_updates, update_time)],
+    )?
+    .and_then(|mut snapshots| snapshots.pop());
+    Ok(snapshot)
+}
+
+/// Fetches the top artists and tracks for all timeframes for each of the provided `(user, stats updates, update time)`
+/// entries, returning one snapshot per entry.  Artists are loaded over `conn` while tracks are loaded over `conn2` in
+/// parallel, so any number of snapshots can be loaded with two connections.
+fn load_stats_snapshots(
+    conn: DbConn,
+    conn2: DbConn,
+    spotify_access_token: &str,
+    entries: &[(&User, &[StatsUpdate], NaiveDateTime)],
+) -> Result<Option<Vec<StatsSnapshot>>, Error> {
     let (artist_stats, track_stats) = match rayon::join(
-        || db_util::get_artist_stats(&user, conn, &spotify_access_token),
-        || db_util::get_track_stats(&user, conn2, &spotify_access_token),
+        move || -> Result<Vec<_>, Error> {
+            entries
+                .iter()
+                .map(|&(user, stats_updates, update_time)| {
+                    db_util::get_artist_stats(
+                        user,
+                        &conn,
+                        spotify_access_token,
+                        stats_updates,
+                        update_time,
+                    )
+                })
+                .collect()
+        },
+        move || -> Result<Vec<_>, Error> {
+            entries
+                .iter()
+                .map(|&(user, stats_updates, update_time)| {
+                    db_util::get_track_stats(
+                        user,
+                        &conn2,
+                        spotify_access_token,
+                        stats_updates,
+                        update_time,
+                    )
+                })
+                .collect()
+        },
     ) {
         (Err(err), _) | (Ok(_), Err(err)) => return Err(err),
-        (Ok(None), _) | (_, Ok(None)) => return Ok(None),
-        (Ok(Some(artist_stats)), Ok(Some(track_stats))) => (artist_stats, track_stats),
+        (Ok(artist_stats), Ok(track_stats)) => (artist_stats, track_stats),
     };
     mark("Fetched artist and track stats");
 
-    let mut snapshot = StatsSnapshot::new(user.last_update_time);
+    let mut snapshots = Vec::with_capacity(entries.len());
+    for ((artist_stats, track_stats), &(_, _, update_time)) in
+        artist_stats.into_iter().zip(track_stats).zip(entries)
+    {
+        let (artist_stats, track_stats) = match (artist_stats, track_stats) {
+            (Some(artist_stats), Some(track_stats)) => (artist_stats, track_stats),
+            _ => return Ok(None),
+        };
 
-    for (timeframe_id, artist) in artist_stats {
-        snapshot.artists.add_item_by_id(timeframe_id, artist);
+        let mut snapshot = StatsSnapshot::new(update_time);
+        for (timeframe_id, artist) in artist_stats {
+            snapshot.artists.add_item_by_id(timeframe_id, artist);
+        }
+        for (timeframe_id, track) in track_stats {
+            snapshot.tracks.add_item_by_id(timeframe_id, track);
+        }
+        snapshots.push(snapshot);
     }
+    mark("Constructed snapshots");
+
+    Ok(Some(snapshots))
+}
+
+#[derive(Serialize)]
+pub struct UserComparison {
+    pub artists_by_id: HashMap<String, Artist>,
+    pub tracks_by_id: HashMap<String, Track>,
+    /// Comparisons keyed by timeframe: "short", "medium", and "long"
+    pub timeframes: HashMap<String, TimeframeComparison>,
+}
+
+/// Compares the current top artists, tracks, and genres of two users
+#[get("/compare/<user_a>/<user_b>")]
+pub fn compare_users(
+    conn: DbConn,
+    conn2: DbConn,
+    token_data: State<Mutex<SpotifyTokenData>>,
+    user_a: String,
+    user_b: String,
+) -> Result<Option<Json<UserComparison>>, Error> {
+    start();
+    let (user_a, user_b) = match (
+        db_util::get_user_by_spotify_id(&conn, &user_a)?,
+        db_util::get_user_by_spotify_id(&conn, &user_b)?,
+    ) {
+        (Some(user_a), Some(user_b)) => (user_a, user_b),
+        _ => return Ok(None),
+    };
+    mark("Finished getting spotify users by id");
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().unwrap();
+        token_data.get()
+    }?;
+    mark("Got spotify access token");
 
-    for (timeframe_id, track) in track_stats {
-        snapshot.tracks.add_item_by_id(timeframe_id, track);
+    let stats_updates_a = db_util::get_stats_updates(&conn, &user_a)?;
+    let stats_updates_b = db_util::get_stats_updates(&conn, &user_b)?;
+    let snapshots = load_stats_snapshots(
+        conn,
+        conn2,
+        &spotify_access_token,
+        &[
+            (&user_a, &stats_updates_a, user_a.last_update_time),
+            (&user_b, &stats_updates_b, user_b.last_update_time),
+        ],
+    )?;
+    let (snapshot_a, snapshot_b) = match snapshots.map(Vec::into_iter) {
+        Some(mut snapshots) => match (snapshots.next(), snapshots.next()) {
+            (Some(snapshot_a), Some(snapshot_b)) => (snapshot_a, snapshot_b),
+            _ => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    let timeframes = snapshot_a
+        .artists
+        .iter()
+        .zip(snapshot_a.tracks.iter())
+        .zip(snapshot_b.artists.iter().zip(snapshot_b.tracks.iter()))
+        .map(
+            |(((timeframe, artists_a), (_, tracks_a)), ((_, artists_b), (_, tracks_b)))| {
+                let comparison =
+                    crate::stats::compare_timeframe(artists_a, tracks_a, artists_b, tracks_b);
+                (timeframe.to_string(), comparison)
+            },
+        )
+        .collect();
+    mark("Computed comparison");
+
+    let mut artists_by_id = HashMap::new();
+    let mut tracks_by_id = HashMap::new();
+    for snapshot in vec![snapshot_a, snapshot_b] {
+        for (_timeframe, artists) in snapshot.artists {
+            for artist in artists {
+                artists_by_id.insert(artist.id.clone(), artist);
+            }
+        }
+        for (_timeframe, tracks) in snapshot.tracks {
+            for track in tracks {
+                tracks_by_id.insert(track.id.clone(), track);
+            }
+        }
     }
-    mark("Constructed snapshot");
 
-    Ok(Some(Json(snapshot)))
+    Ok(Some(Json(UserComparison {
+        artists_by_id,
+        tracks_by_id,
+        timeframes,
+    })))
 }
 
 #[derive(Serialize)]
@@ -76,17 +347,22 @@ pub struct ArtistStats {
     pub tracks_by_id: HashMap<String, Track>,
     pub popularity_history: Vec<(NaiveDateTime, [Option<u16>; 3])>,
     pub top_tracks: Vec<(String, usize)>,
+    /// Global follower count + popularity of the artist over time, independent of the user
+    pub global_stats_history: Vec<ArtistStatsHistoryEntry>,
 }
 
-#[get("/stats/<username>/artist/<artist_id>")]
+#[get("/stats/<username>/artist/<artist_id>?<filter..>")]
 pub fn get_artist_stats(
     conn: DbConn,
     conn2: DbConn,
+    conn3: DbConn,
     token_data: State<Mutex<SpotifyTokenData>>,
     username: String,
     artist_id: String,
-) -> Result<Option<Json<ArtistStats>>, String> {
+    filter: Form<HistoryFilterParams>,
+) -> Result<Option<Json<ArtistStats>>, Error> {
     start();
+    let filter = filter.into_inner().into_filter();
     let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
         Some(user) => user,
         None => {
@@ -102,13 +378,22 @@ pub fn get_artist_stats(
     mark("Got spotify access token");
 
     let (artist_popularity_history, (tracks_by_id, top_track_scores)) = match rayon::join(
-        || crate::db_util::get_artist_rank_history_single_artist(&user, conn, &artist_id),
-        || -> Result<Option<(HashMap<String, Track>, Vec<(String, usize)>)>, String> {
+        || {
+            db_util::get_rank_history_single_entity(
+                &user,
+                conn,
+                SnapshotEntity::Artists,
+                &artist_id,
+                &filter,
+            )
+        },
+        || -> Result<Option<(HashMap<String, Track>, Vec<(String, usize)>)>, Error> {
             let (tracks_by_id, track_history) = match db_util::get_track_stats_history(
                 &user,
                 conn2,
                 &spotify_access_token,
                 &artist_id,
+                &filter,
             )? {
                 Some(res) => res,
                 None => return Ok(None),
@@ -133,27 +418,293 @@ pub fn get_artist_stats(
     };
     mark("Found matching artist to use");
 
+    let global_stats_history =
+        db_util::get_artist_global_stats_history(conn3, &artist_id, &filter)?;
+    mark("Fetched global artist stats history");
+
     let stats = ArtistStats {
         artist,
         tracks_by_id,
         popularity_history: artist_popularity_history,
         top_tracks: top_track_scores,
+        global_stats_history,
     };
     Ok(Some(Json(stats)))
 }
 
+#[derive(Serialize)]
+pub struct TrackArtistRankings {
+    pub artist: Artist,
+    /// The user's current ranking of the artist for the short, medium, and long timeframes
+    pub rankings: [Option<u16>; 3],
+}
+
+#[derive(Serialize)]
+pub struct TrackStats {
+    pub track: Track,
+    pub popularity_history: Vec<(NaiveDateTime, [Option<u16>; 3])>,
+    pub artists: Vec<TrackArtistRankings>,
+}
+
+#[get("/stats/<username>/track/<track_id>?<filter..>")]
+pub fn get_track_stats(
+    conn: DbConn,
+    conn2: DbConn,
+    token_data: State<Mutex<SpotifyTokenData>>,
+    username: String,
+    track_id: String,
+    filter: Form<HistoryFilterParams>,
+) -> Result<Option<Json<TrackStats>>, Error> {
+    start();
+    let filter = filter.into_inner().into_filter();
+    let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        }
+    };
+    mark("Finished getting spotify user by id");
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().unwrap();
+        token_data.get()
+    }?;
+    mark("Got spotify access token");
+
+    let (track_popularity_history, artist_rankings) = match rayon::join(
+        || {
+            db_util::get_rank_history_single_entity(
+                &user,
+                conn,
+                SnapshotEntity::Tracks,
+                &track_id,
+                &filter,
+            )
+        },
+        || db_util::get_track_artists_rankings(&user, conn2, &track_id),
+    ) {
+        (Err(err), _) | (Ok(_), Err(err)) => return Err(err),
+        (Ok(None), _) | (_, Ok(None)) => return Ok(None),
+        (Ok(Some(a)), Ok(Some(b))) => (a, b),
+    };
+    mark("Fetched track stats and artist rankings");
+
+    let track = match crate::spotify_api::fetch_tracks(&spotify_access_token, &[&track_id])?
+        .drain(..)
+        .next()
+    {
+        Some(track) => track,
+        None => return Ok(None),
+    };
+    mark("Found matching track to use");
+
+    let artist_spotify_ids: Vec<&str> = artist_rankings
+        .iter()
+        .map(|(artist_spotify_id, _)| artist_spotify_id.as_str())
+        .collect();
+    let artists = crate::spotify_api::fetch_artists(&spotify_access_token, &artist_spotify_ids)?
+        .into_iter()
+        .zip(artist_rankings.into_iter())
+        .map(|(artist, (_, rankings))| TrackArtistRankings { artist, rankings })
+        .collect();
+    mark("Fetched track artists metadata");
+
+    Ok(Some(Json(TrackStats {
+        track,
+        popularity_history: track_popularity_history,
+        artists,
+    })))
+}
+
+#[derive(Serialize)]
+pub struct AlbumStats {
+    pub albums_by_id: HashMap<String, Album>,
+    pub tracks_by_id: HashMap<String, Track>,
+    pub scores: AlbumScores,
+}
+
+#[get("/stats/<username>/albums?<filter..>")]
+pub fn get_album_stats(
+    conn: DbConn,
+    token_data: State<Mutex<SpotifyTokenData>>,
+    username: String,
+    filter: Form<HistoryFilterParams>,
+) -> Result<Option<Json<AlbumStats>>, Error> {
+    let filter = filter.into_inner().into_filter();
+    let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        }
+    };
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().unwrap();
+        token_data.get()
+    }?;
+
+    let (tracks_by_id, track_stats_history) =
+        match db_util::get_all_track_stats_history(&user, conn, &spotify_access_token, &filter)? {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+    if track_stats_history.is_empty() {
+        return Ok(None);
+    }
+
+    let scores = crate::stats::compute_album_scores(&tracks_by_id, &track_stats_history);
+    let albums_by_id = tracks_by_id
+        .values()
+        .map(|track| (track.album.id.clone(), track.album.clone()))
+        .collect();
+
+    Ok(Some(Json(AlbumStats {
+        albums_by_id,
+        tracks_by_id,
+        scores,
+    })))
+}
+
+#[derive(Serialize)]
+pub struct StatsDiff {
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub artists_by_id: HashMap<String, Artist>,
+    pub tracks_by_id: HashMap<String, Track>,
+    /// Artist ranking diffs keyed by timeframe
+    pub artists: HashMap<String, RankingsDiff>,
+    /// Track ranking diffs keyed by timeframe
+    pub tracks: HashMap<String, RankingsDiff>,
+}
+
+/// Splits a snapshot into the IDs of the ranked artists and tracks for each timeframe, adding their metadata to the
+/// provided maps
+fn get_snapshot_ids(
+    snapshot: StatsSnapshot,
+    artists_by_id: &mut HashMap<String, Artist>,
+    tracks_by_id: &mut HashMap<String, Track>,
+) -> (TimeFrames<String>, TimeFrames<String>) {
+    let mut artist_ids = TimeFrames::default();
+    for (timeframe, artists) in snapshot.artists {
+        for artist in artists {
+            artist_ids.add_item(timeframe, artist.id.clone());
+            artists_by_id.insert(artist.id.clone(), artist);
+        }
+    }
+
+    let mut track_ids = TimeFrames::default();
+    for (timeframe, tracks) in snapshot.tracks {
+        for track in tracks {
+            track_ids.add_item(timeframe, track.id.clone());
+            tracks_by_id.insert(track.id.clone(), track);
+        }
+    }
+
+    (artist_ids, track_ids)
+}
+
+/// Returns the artists and tracks that entered, dropped out of, or moved within the user's rankings between the
+/// updates closest to `from` and `to`.  Defaults to the user's last two updates.
+#[get("/stats/<username>/diff?<from>&<to>")]
+pub fn get_stats_diff(
+    conn: DbConn,
+    conn2: DbConn,
+    token_data: State<Mutex<SpotifyTokenData>>,
+    username: String,
+    from: Option<TimestampParam>,
+    to: Option<TimestampParam>,
+) -> Result<Option<Json<StatsDiff>>, Error> {
+    start();
+    let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
+        Some(user) => user,
+        None => {
+            return Ok(None);
+        }
+    };
+    mark("Finished getting spotify user by id");
+
+    let stats_updates = db_util::get_stats_updates(&conn, &user)?;
+    let to_time = match to {
+        Some(TimestampParam(to)) => db_util::get_closest_update_time(&stats_updates, to),
+        None => stats_updates.last().map(|update| update.update_time),
+    };
+    let from_time = match from {
+        Some(TimestampParam(from)) => db_util::get_closest_update_time(&stats_updates, from),
+        None => to_time.and_then(|to_time| {
+            stats_updates
+                .iter()
+                .rev()
+                .map(|update| update.update_time)
+                .find(|&update_time| update_time < to_time)
+        }),
+    };
+    let (from_time, to_time) = match (from_time, to_time) {
+        (Some(from_time), Some(to_time)) => (from_time, to_time),
+        _ => return Ok(None),
+    };
+    if from_time >= to_time {
+        return Err(Error::BadRequest(
+            "`from` must resolve to an earlier update than `to`".into(),
+        ));
+    }
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().unwrap();
+        token_data.get()
+    }?;
+    mark("Got spotify access token");
+
+    // Only the two snapshots being compared are loaded, each with its rankings carried forward from earlier updates
+    let snapshots = load_stats_snapshots(
+        conn,
+        conn2,
+        &spotify_access_token,
+        &[
+            (&user, &stats_updates, from_time),
+            (&user, &stats_updates, to_time),
+        ],
+    )?;
+    let (from_snapshot, to_snapshot) = match snapshots.map(Vec::into_iter) {
+        Some(mut snapshots) => match (snapshots.next(), snapshots.next()) {
+            (Some(from_snapshot), Some(to_snapshot)) => (from_snapshot, to_snapshot),
+            _ => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    let mut artists_by_id = HashMap::new();
+    let mut tracks_by_id = HashMap::new();
+    let (from_artist_ids, from_track_ids) =
+        get_snapshot_ids(from_snapshot, &mut artists_by_id, &mut tracks_by_id);
+    let (to_artist_ids, to_track_ids) =
+        get_snapshot_ids(to_snapshot, &mut artists_by_id, &mut tracks_by_id);
+    let artists = crate::stats::diff_timeframes(&from_artist_ids, &to_artist_ids);
+    let tracks = crate::stats::diff_timeframes(&from_track_ids, &to_track_ids);
+    mark("Computed diff");
+
+    Ok(Some(Json(StatsDiff {
+        from: from_time,
+        to: to_time,
+        artists_by_id,
+        tracks_by_id,
+        artists,
+        tracks,
+    })))
+}
+
 #[derive(Serialize)]
 pub struct GenresHistory {
     pub timestamps: Vec<NaiveDateTime>,
     pub history_by_genre: HashMap<String, Vec<Option<usize>>>,
 }
 
-#[get("/stats/<username>/genre_history")]
+#[get("/stats/<username>/genre_history?<filter..>")]
 pub fn get_genre_history(
     conn: DbConn,
     token_data: State<Mutex<SpotifyTokenData>>,
     username: String,
-) -> Result<Option<Json<GenresHistory>>, String> {
+    filter: Form<HistoryFilterParams>,
+) -> Result<Option<Json<GenresHistory>>, Error> {
+    let mut filter = filter.into_inner().into_filter();
     let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
         Some(user) => user,
         None => {
@@ -165,9 +716,10 @@ pub fn get_genre_history(
         token_data.get()
     }?;
 
-    // Only include data from the "short" timeframe since we're producing a timeseries
+    // Default to only including data from the "short" timeframe since we're producing a timeseries
+    filter.timeframe = filter.timeframe.or(Some(0));
     let (artists_by_id, artist_stats_history) =
-        match db_util::get_artist_stats_history(&user, conn, &spotify_access_token, Some(0))? {
+        match db_util::get_artist_stats_history(&user, conn, &spotify_access_token, &filter)? {
             Some(res) => res,
             None => return Ok(None),
         };
@@ -188,13 +740,15 @@ pub struct GenreStats {
     pub popularity_history: TimeFrames<usize>,
 }
 
-#[get("/stats/<username>/genre/<genre>")]
+#[get("/stats/<username>/genre/<genre>?<filter..>")]
 pub fn get_genre_stats(
     conn: DbConn,
     token_data: State<Mutex<SpotifyTokenData>>,
     username: String,
     genre: String,
-) -> Result<Option<Json<GenreStats>>, String> {
+    filter: Form<HistoryFilterParams>,
+) -> Result<Option<Json<GenreStats>>, Error> {
+    let filter = filter.into_inner().into_filter();
     let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
         Some(user) => user,
         None => {
@@ -206,11 +760,16 @@ pub fn get_genre_stats(
         token_data.get()
     }?;
 
-    let (artists_by_id, genre_stats_history) =
-        match db_util::get_genre_stats_history(&user, conn, &spotify_access_token, &genre)? {
-            Some(res) => res,
-            None => return Ok(None),
-        };
+    let (artists_by_id, genre_stats_history) = match db_util::get_genre_stats_history(
+        &user,
+        conn,
+        &spotify_access_token,
+        &genre,
+        &filter,
+    )? {
+        Some(res) => res,
+        None => return Ok(None),
+    };
 
     // Compute ranking scores for each of the update items
     let (timestamps, ranking_by_artist_spotify_id_by_timeframe, popularity_history) =
@@ -224,6 +783,109 @@ pub fn get_genre_stats(
     })))
 }
 
+/// Streams all of the artist and track rank snapshots stored for a user along with their metadata, as either JSON lines
+/// (the default) or CSV.  Users can only export their own data.
+#[get("/stats/<username>/export?<format>")]
+pub fn export_user_data(
+    conn: DbConn,
+    auth: SpotifyUserAuth,
+    token_data: State<Mutex<SpotifyTokenData>>,
+    username: String,
+    format: Option<ExportFormat>,
+) -> Result<Content<Stream<SnapshotExportReader>>, Error> {
+    if !auth.is_user(&username) {
+        return Err(Error::Forbidden("You can only export your own data".into()));
+    }
+
+    let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
+        Some(user) => user,
+        None => return Err(Error::NotFound("User not found".into())),
+    };
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().unwrap();
+        token_data.get()
+    }?;
+
+    let format = format.unwrap_or(ExportFormat::JsonLines);
+    let reader = SnapshotExportReader::new(conn, &user, spotify_access_token, format);
+    Ok(Content(format.get_content_type(), Stream::from(reader)))
+}
+
+/// Imports a streaming history file from a Spotify account data export (either `StreamingHistory*.json` or the
+/// extended `endsong_*.json`), storing all of the plays in it as play events.  Users can only import their own data.
+#[post("/stats/<username>/import", data = "<data>")]
+pub fn import_streaming_history(
+    conn: DbConn,
+    auth: SpotifyUserAuth,
+    token_data: State<Mutex<SpotifyTokenData>>,
+    username: String,
+    data: Data,
+) -> Result<Json<ImportSummary>, Error> {
+    if !auth.is_user(&username) {
+        return Err(Error::Forbidden("You can only import your own data".into()));
+    }
+
+    let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
+        Some(user) => user,
+        None => return Err(Error::NotFound("User not found".into())),
+    };
+
+    let mut body = String::new();
+    data.open()
+        .take(MAX_IMPORT_FILE_SIZE)
+        .read_to_string(&mut body)
+        .map_err(|err| -> Error {
+            info!("Error reading uploaded streaming history file: {:?}", err);
+            Error::BadRequest("Streaming history file must be valid UTF-8".into())
+        })?;
+    let (plays, summary) = crate::import::parse_streaming_history(&body)?;
+
+    let spotify_access_token = {
+        let token_data = &mut *(&*token_data).lock().unwrap();
+        token_data.get()
+    }?;
+
+    // Imports can search for thousands of tracks, so keep them from tying up the threads used for interactive requests
+    let summary = crate::spotify_api::run_as_background_work(|| {
+        crate::import::import_streaming_history(
+            &conn,
+            &user,
+            &spotify_access_token,
+            &plays,
+            summary,
+        )
+    })?;
+    info!(
+        "Imported streaming history for user {}: {:?}",
+        user.username, summary
+    );
+    Ok(Json(summary))
+}
+
+/// Permanently deletes a user's account along with all of the data stored for them.  Users can only delete their own
+/// account.  See `db_util::delete_user_account` for details.
+#[delete("/stats/<username>")]
+pub fn delete_user_account(
+    conn: DbConn,
+    auth: SpotifyUserAuth,
+    username: String,
+) -> Result<Json<AccountDeletionReport>, Error> {
+    if !auth.is_user(&username) {
+        return Err(Error::Forbidden(
+            "You can only delete your own account".into(),
+        ));
+    }
+
+    let user = match db_util::get_user_by_spotify_id(&conn, &username)? {
+        Some(user) => user,
+        None => return Err(Error::NotFound("User not found".into())),
+    };
+
+    let report = db_util::delete_user_account(&conn, &user)?;
+    info!("Deleted account for user {}: {:?}", user.username, report);
+    Ok(Json(report))
+}
+
 /// Redirects to the Spotify authorization page for the application
 #[get("/authorize")]
 pub fn authorize() -> Redirect {
@@ -231,10 +893,8 @@ pub fn authorize() -> Redirect {
     let callback_uri = crate::conf::CONF.get_absolute_oauth_cb_uri();
 
     Redirect::to(format!(
-        "https://accounts.spotify.com/authorize?client_id={}&response_type=code&redirect_uri={}&scope={}",
-        CONF.client_id,
-        callback_uri,
-        scopes
+        "{}/authorize?client_id={}&response_type=code&redirect_uri={}&scope={}",
+        CONF.spotify_accounts_base_url, CONF.client_id, callback_uri, scopes
     ))
 }
 
@@ -242,10 +902,12 @@ pub fn authorize() -> Redirect {
 /// authentication request and handles retrieving user tokens, creating an entry for the user in the
 /// users table, and fetching an initial stats snapshot.
 #[get("/oauth_cb?<error>&<code>")]
-pub fn oauth_cb(conn: DbConn, error: Option<&RawStr>, code: &RawStr) -> Result<Redirect, String> {
+pub fn oauth_cb(conn: DbConn, error: Option<&RawStr>, code: &RawStr) -> Result<Redirect, Error> {
     if error.is_some() {
         error!("Error during Oauth authorization process: {:?}", error);
-        return Err("An error occured while authenticating with Spotify.".into());
+        return Err(Error::Unauthorized(
+            "An error occured while authenticating with Spotify.".into(),
+        ));
     }
 
     let oauth_cb_url = crate::conf::CONF.get_absolute_oauth_cb_uri();
@@ -258,30 +920,36 @@ pub fn oauth_cb(conn: DbConn, error: Option<&RawStr>, code: &RawStr) -> Result<R
     params.insert("client_id", CONF.client_id.as_str());
     params.insert("client_secret", CONF.client_secret.as_str());
 
-    let client = reqwest::blocking::Client::new();
     info!("Making request to fetch user token from OAuth CB response...");
-    let res = client
-        .post(SPOTIFY_TOKEN_FETCH_URL)
+    let res = crate::spotify_api::HTTP_CLIENT
+        .post(&CONF.get_spotify_accounts_url(crate::spotify_api::SPOTIFY_TOKEN_PATH))
         .form(&params)
         .send()
-        .map_err(|_| -> String {
-            "Error fetching token from Spotify from response Oauth code".into()
+        .map_err(|err| -> Error {
+            Error::http(
+                "Error fetching token from Spotify from response Oauth code",
+                err,
+            )
         })?;
 
     let res: OAuthTokenResponse = match res.json() {
         Ok(res) => res,
         Err(err) => {
             error!("Failed to fetch user tokens from OAuth CB code: {:?}", err);
-            return Err("Error parsing response from token fetch endpoint".into());
+            return Err(Error::http(
+                "Error parsing response from token fetch endpoint",
+                err,
+            ));
         }
     };
 
-    let (access_token, refresh_token) = match res {
+    let (access_token, refresh_token, expires_in) = match res {
         OAuthTokenResponse::Success {
             access_token,
             refresh_token,
+            expires_in,
             ..
-        } => (access_token, refresh_token),
+        } => (access_token, refresh_token, expires_in),
         OAuthTokenResponse::Error {
             error,
             error_description,
@@ -290,7 +958,10 @@ pub fn oauth_cb(conn: DbConn, error: Option<&RawStr>, code: &RawStr) -> Result<R
                 "Error fetching tokens for user: {}; {}",
                 error, error_description
             );
-            return Err("Error fetching user access tokens from Spotify API.".into());
+            return Err(Error::spotify_api(
+                None,
+                "Error fetching user access tokens from Spotify API.",
+            ));
         }
     };
 
@@ -301,13 +972,16 @@ pub fn oauth_cb(conn: DbConn, error: Option<&RawStr>, code: &RawStr) -> Result<R
     let user_spotify_id = user_profile_info.id;
     let username = user_profile_info.display_name;
 
+    let access_token_expires_at =
+        Utc::now().naive_utc() + chrono::Duration::seconds(expires_in as i64);
     let user = NewUser {
         creation_time: Utc::now().naive_utc(),
         last_update_time: Utc::now().naive_utc(),
         spotify_id: user_spotify_id.clone(),
         username: username.clone(),
-        token: access_token,
-        refresh_token,
+        token: access_token.clone(),
+        refresh_token: refresh_token.clone(),
+        access_token_expires_at: Some(access_token_expires_at),
     };
 
     match diesel::insert_into(crate::schema::users::table)
@@ -317,10 +991,21 @@ pub fn oauth_cb(conn: DbConn, error: Option<&RawStr>, code: &RawStr) -> Result<R
         Err(diesel::result::Error::DatabaseError(
             diesel::result::DatabaseErrorKind::UniqueViolation,
             _,
-        )) => info!("Already have a row for user; skipping manual update and redirecting diretly."),
+        )) => {
+            // The user has authorized before, possibly after revoking access.  Store the new tokens and re-enable
+            // updates for them in case they had been disabled.
+            info!("Already have a row for user; storing new tokens and redirecting directly.");
+            crate::db_util::reauthorize_user(
+                &conn,
+                &user_spotify_id,
+                &access_token,
+                &refresh_token,
+                access_token_expires_at,
+            )?;
+        }
         Err(err) => {
             error!("Error inserting row: {:?}", err);
-            return Err("Error inserting user into database".into());
+            return Err(Error::database("Error inserting user into database", err));
         }
         Ok(_) => {
             // Retrieve the inserted user row
@@ -335,11 +1020,21 @@ pub fn oauth_cb(conn: DbConn, error: Option<&RawStr>, code: &RawStr) -> Result<R
                         "Failed to fetch stats for user \"{}\"; bad response from Spotify API?",
                         username
                     );
-                    return Err("Error fetching user stats from the Spotify API.".into());
+                    return Err(Error::spotify_api(
+                        None,
+                        "Error fetching user stats from the Spotify API.",
+                    ));
                 }
             };
 
             crate::spotify_api::store_stats_snapshot(&conn, &user, cur_user_stats)?;
+
+            if let Err(err) = crate::spotify_api::ingest_recently_played(&conn, &user) {
+                error!(
+                    "Error ingesting recently played tracks for new user \"{}\": {}",
+                    username, err
+                );
+            }
         }
     };
 
@@ -351,96 +1046,141 @@ pub fn oauth_cb(conn: DbConn, error: Option<&RawStr>, code: &RawStr) -> Result<R
 }
 
 /// Returns `true` if the token is valid, false if it's not
-fn validate_api_token(api_token_data: rocket::data::Data) -> Result<bool, String> {
+fn validate_api_token(api_token_data: rocket::data::Data) -> Result<bool, Error> {
     let mut api_token: String = String::new();
     api_token_data
         .open()
         .take(1024 * 1024)
         .read_to_string(&mut api_token)
-        .map_err(|err| {
+        .map_err(|err| -> Error {
             error!("Error reading provided admin API token: {:?}", err);
-            String::from("Error reading post data body")
+            Error::BadRequest("Error reading post data body".into())
         })
         .map(|_| api_token == CONF.admin_api_token)
 }
 
-/// This route is internal and hit by the cron job that is called to periodically update the stats
-/// for the least recently updated user.
+/// This route is internal and can be used to manually trigger an update for the least recently updated user.  Updates
+/// are normally run automatically by the scheduler in `scheduler.rs`.
 #[post("/update_user", data = "<api_token_data>")]
 pub fn update_user(
     conn: DbConn,
     api_token_data: rocket::data::Data,
-) -> Result<status::Custom<String>, String> {
+) -> Result<status::Custom<String>, Error> {
     use crate::schema::users::dsl::*;
 
     if !validate_api_token(api_token_data)? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
+        return Err(Error::Unauthorized("Invalid API token supplied".into()));
     }
 
     // Get the least recently updated user
-    let mut user: User =
-        users
-            .order_by(last_update_time)
-            .first(&conn.0)
-            .map_err(|err| -> String {
-                error!("{:?}", err);
-                "Error querying user to update from database".into()
-            })?;
-
-    // Update the access token for that user using the refresh token
-    let updated_access_token = match crate::spotify_api::refresh_user_token(&user.refresh_token) {
-        Ok(updated_access_token) => updated_access_token,
-        Err(_) => {
-            db_util::update_user_last_updated(&user, &conn, Utc::now().naive_utc())?;
-
-            // TODO: Disable auto-updates for the user that has removed their permission grant to prevent wasted updates in the future
-            let msg = format!("Failed to refresh user token for user {}; updating last updated timestamp and not updating.", user.username);
-            info!("{}", msg);
-            return Ok(status::Custom(Status::Unauthorized, msg));
-        }
-    };
-    diesel::update(users.filter(id.eq(user.id)))
-        .set(token.eq(&updated_access_token))
-        .execute(&conn.0)
-        .map_err(|err| -> String {
+    let user: User = users
+        .filter(disabled.eq(false))
+        .order_by(last_update_time)
+        .first(&conn.0)
+        .map_err(|err| -> Error {
             error!("{:?}", err);
-            "Error updating user with new access token".into()
+            Error::database("Error querying user to update from database", err)
         })?;
-    user.token = updated_access_token;
-
-    // Only update the user if it's been longer than the minimum update interval
-    let min_update_interval_seconds = crate::conf::CONF.min_update_interval;
-    let now = chrono::Utc::now().naive_utc();
-    let diff = now - user.last_update_time;
-    if diff < min_update_interval_seconds {
-        let msg = format!(
-            "{} since last update; not updating anything right now.",
-            diff
-        );
-        info!("{}", msg);
-        return Ok(status::Custom(Status::Ok, msg));
+    let user_name = user.username.clone();
+
+    match crate::scheduler::update_user_stats(&conn, user)? {
+        UpdateOutcome::Updated => Ok(status::Custom(
+            Status::Ok,
+            format!("Successfully updated user {}", user_name),
+        )),
+        UpdateOutcome::NotDue(diff) => Ok(status::Custom(
+            Status::Ok,
+            format!(
+                "{} since last update; not updating anything right now.",
+                diff
+            ),
+        )),
+        UpdateOutcome::TokenRefreshFailed(false) => Ok(status::Custom(
+            Status::Unauthorized,
+            format!("Failed to refresh user token for user {}; updating last updated timestamp and not updating.", user_name),
+        )),
+        UpdateOutcome::TokenRefreshFailed(true) => Ok(status::Custom(
+            Status::Unauthorized,
+            format!("Failed to refresh user token for user {}; disabling updates for them.", user_name),
+        )),
+        UpdateOutcome::Disabled => Ok(status::Custom(
+            Status::Ok,
+            format!("User {} is disabled; not updating.", user_name),
+        )),
     }
-    info!("{} since last update; proceeding with update.", diff);
+}
 
-    let stats = match crate::spotify_api::fetch_cur_stats(&user)? {
-        Some(stats) => stats,
-        None => {
-            error!(
-                "Error when fetching stats for user {:?}; no stats returned.",
-                user
-            );
-            return Err("No data from Spotify API for that user".into());
-        }
-    };
+/// Returns the current state of the background update scheduler
+#[get("/scheduler_status")]
+pub fn get_scheduler_status() -> Json<SchedulerStatus> {
+    Json(crate::scheduler::SCHEDULER_STATUS.lock().unwrap().clone())
+}
+
+/// Returns metrics in the Prometheus text exposition format
+#[get("/metrics")]
+pub fn get_metrics(db_pool: State<DbConnPool>) -> Content<String> {
+    let db_pool_state = db_pool.0.state();
+    let mut pools = vec![PoolUsage {
+        name: "mysql",
+        max_size: db_pool.0.max_size(),
+        connections: db_pool_state.connections,
+        idle_connections: db_pool_state.idle_connections,
+    }];
+    // Touching the Redis pool creates it, so only report it if it's actually used
+    if CONF.cache_backend != CacheBackend::Memory {
+        let redis_pool_state = crate::cache::REDIS_CONN_POOL.state();
+        pools.push(PoolUsage {
+            name: "redis",
+            max_size: crate::cache::REDIS_CONN_POOL.max_size(),
+            connections: redis_pool_state.connections,
+            idle_connections: redis_pool_state.idle_connections,
+        });
+    }
+    let scheduler_queue_depth = crate::scheduler::SCHEDULER_STATUS
+        .lock()
+        .unwrap()
+        .queue_depth;
+
+    Content(
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        crate::metrics::render(&pools, scheduler_queue_depth),
+    )
+}
+
+/// Internal route that removes snapshots which were only partially stored.  See
+/// `db_util::repair_partial_snapshots` for details.  Pass `dry_run=true` to report what would be removed without
+/// changing anything.
+#[post("/repair_partial_snapshots?<dry_run>", data = "<api_token_data>")]
+pub fn repair_partial_snapshots(
+    conn: DbConn,
+    dry_run: Option<bool>,
+    api_token_data: rocket::data::Data,
+) -> Result<status::Custom<String>, Error> {
+    if !validate_api_token(api_token_data)? {
+        return Err(Error::Unauthorized("Invalid API token supplied".into()));
+    }
 
-    crate::spotify_api::store_stats_snapshot(&conn, &user, stats)?;
+    let dry_run = dry_run.unwrap_or(false);
+    let report = crate::db_util::repair_partial_snapshots(&conn, dry_run)?;
+    info!(
+        "Repaired partial snapshots (dry run: {}): {:?}",
+        dry_run, report
+    );
 
     Ok(status::Custom(
         Status::Ok,
-        format!("Successfully updated user {}", user.username),
+        format!(
+            "{}.  Orphaned updates removed: {}; partial updates removed: {}; timeframes moved: {}; users reset: {}",
+            if dry_run {
+                "Dry run; nothing was changed"
+            } else {
+                "Successfully repaired partial snapshots"
+            },
+            report.orphaned_updates_removed,
+            report.partial_updates_removed,
+            report.timeframes_moved,
+            report.users_reset
+        ),
     ))
 }
 
@@ -449,12 +1189,9 @@ pub fn populate_tracks_artists_mapping_table(
     conn: DbConn,
     api_token_data: rocket::data::Data,
     token_data: State<Mutex<SpotifyTokenData>>,
-) -> Result<status::Custom<String>, String> {
+) -> Result<status::Custom<String>, Error> {
     if !validate_api_token(api_token_data)? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
+        return Err(Error::Unauthorized("Invalid API token supplied".into()));
     }
 
     let spotify_access_token = {
@@ -475,12 +1212,9 @@ pub fn populate_artists_genres_mapping_table(
     conn: DbConn,
     api_token_data: rocket::data::Data,
     token_data: State<Mutex<SpotifyTokenData>>,
-) -> Result<status::Custom<String>, String> {
+) -> Result<status::Custom<String>, Error> {
     if !validate_api_token(api_token_data)? {
-        return Ok(status::Custom(
-            Status::Unauthorized,
-            "Invalid API token supplied".into(),
-        ));
+        return Err(Error::Unauthorized("Invalid API token supplied".into()));
     }
 
     let spotify_access_token = {
@@ -495,3 +1229,19 @@ pub fn populate_artists_genres_mapping_table(
         "Sucessfully populated mapping table".into(),
     ))
 }
+
+#[test]
+fn timestamp_param_parsing() {
+    let parse = |value: &'static str| {
+        TimestampParam::from_form_value(RawStr::from_str(value)).map(|TimestampParam(dt)| dt)
+    };
+
+    let expected = chrono::NaiveDate::from_ymd(2020, 6, 1).and_hms(12, 0, 0);
+    assert_eq!(parse("1591012800"), Ok(expected));
+    assert_eq!(parse("2020-06-01T12:00:00"), Ok(expected));
+    assert_eq!(parse("2020-06-01T14:00:00%2B02:00"), Ok(expected));
+    // Out of range for `NaiveDateTime`
+    assert!(parse("9223372036854775807").is_err());
+    assert!(parse("-9223372036854775808").is_err());
+    assert!(parse("yesterday").is_err());
+}
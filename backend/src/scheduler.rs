@@ -0,0 +1,356 @@
+//! Background scheduler that keeps user stats up to date.  It periodically looks up all users whose last
+//! update is older than the configured minimum update interval and runs updates for them on a fixed-size
+//! pool of worker threads.
+
+use std::sync::Mutex;
+use std::thread;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use crossbeam::channel::{self, Receiver, Sender};
+use hashbrown::HashMap;
+use rocket::Rocket;
+
+use crate::conf::CONF;
+use crate::db_util;
+use crate::error::Error;
+use crate::models::User;
+use crate::{DbConn, DbConnPool};
+
+type Pool = diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::MysqlConnection>>;
+
+#[derive(Debug)]
+pub enum UpdateOutcome {
+    /// A new stats snapshot was fetched and stored for the user
+    Updated,
+    /// The user was updated more recently than the minimum update interval; contains the time since the last update
+    NotDue(Duration),
+    /// Spotify rejected the user's refresh token, most likely because they revoked access.  Contains `true` if the user
+    /// was disabled as a result.  Temporary token refresh failures are returned as errors instead so that the scheduler
+    /// backs off before retrying them.
+    TokenRefreshFailed(bool),
+    /// The user has been disabled and won't be updated until they re-authorize
+    Disabled,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SchedulerRun {
+    pub start_time: NaiveDateTime,
+    pub end_time: NaiveDateTime,
+    pub users_updated: usize,
+    pub users_skipped: usize,
+    pub users_failed: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SchedulerStatus {
+    pub enabled: bool,
+    pub concurrency: usize,
+    /// Number of users from the current run that haven't finished updating yet
+    pub queue_depth: usize,
+    /// Number of users whose last update failed and who are waiting to be retried
+    pub users_backing_off: usize,
+    pub cur_run_start_time: Option<NaiveDateTime>,
+    pub last_run: Option<SchedulerRun>,
+}
+
+lazy_static! {
+    pub static ref SCHEDULER_STATUS: Mutex<SchedulerStatus> = Mutex::new(SchedulerStatus {
+        enabled: false,
+        concurrency: 0,
+        queue_depth: 0,
+        users_backing_off: 0,
+        cur_run_start_time: None,
+        last_run: None,
+    });
+}
+
+/// If it's been longer than the minimum update interval since the provided user's last update, fetches and stores a
+/// new stats snapshot for them.  Their access token is refreshed first if it has expired.
+pub fn update_user_stats(conn: &DbConn, mut user: User) -> Result<UpdateOutcome, Error> {
+    if user.disabled {
+        return Ok(UpdateOutcome::Disabled);
+    }
+
+    // Only update the user if it's been longer than the minimum update interval
+    let now = Utc::now().naive_utc();
+    let diff = now - user.last_update_time;
+    if diff < CONF.min_update_interval {
+        info!(
+            "{} since last update for user {}; not updating anything right now.",
+            diff, user.username
+        );
+        return Ok(UpdateOutcome::NotDue(diff));
+    }
+    info!(
+        "{} since last update for user {}; proceeding with update.",
+        diff, user.username
+    );
+
+    // Refresh the access token for the user using their refresh token if it has expired
+    if user.access_token_expired() {
+        match crate::spotify_api::refresh_user_token(&user.refresh_token) {
+            Ok(res) => db_util::store_refreshed_user_token(&conn, &mut user, res)?,
+            Err(err) => {
+                let recorded = db_util::record_token_refresh_failure(&conn, &user, &err)?;
+                let was_disabled = match recorded {
+                    Some(was_disabled) => was_disabled,
+                    None => {
+                        warn!(
+                            "Failed to refresh user token for user {} due to a temporary error; will retry after backing off: {}",
+                            user.username, err
+                        );
+                        return Err(err);
+                    }
+                };
+                if was_disabled {
+                    warn!(
+                        "Spotify rejected the refresh token for user {} {} times in a row; disabling updates for them.",
+                        user.username,
+                        user.refresh_failure_count + 1
+                    );
+                } else {
+                    info!(
+                        "Spotify rejected the refresh token for user {}; updating last updated timestamp and not updating.",
+                        user.username
+                    );
+                }
+                return Ok(UpdateOutcome::TokenRefreshFailed(was_disabled));
+            }
+        }
+    }
+
+    let stats = match crate::spotify_api::fetch_cur_stats(&user)? {
+        Some(stats) => stats,
+        None => {
+            error!(
+                "Error when fetching stats for user {:?}; no stats returned.",
+                user
+            );
+            return Err(Error::spotify_api(
+                None,
+                "No data from Spotify API for that user",
+            ));
+        }
+    };
+
+    crate::spotify_api::store_stats_snapshot(&conn, &user, stats)?;
+
+    // Failing to pull the user's recently played tracks shouldn't invalidate the snapshot we just stored
+    if let Err(err) = crate::spotify_api::ingest_recently_played(&conn, &user) {
+        error!(
+            "Error ingesting recently played tracks for user {}: {}",
+            user.username, err
+        );
+    }
+
+    Ok(UpdateOutcome::Updated)
+}
+
+fn get_conn(pool: &Pool) -> Result<DbConn, Error> {
+    pool.get().map(DbConn).map_err(|err| -> Error {
+        error!("Error getting database connection for scheduler: {:?}", err);
+        Error::database("Error getting database connection for scheduler", err)
+    })
+}
+
+fn run_update_worker(
+    pool: Pool,
+    jobs: Receiver<User>,
+    results: Sender<(i64, Result<UpdateOutcome, Error>)>,
+) {
+    for user in jobs.iter() {
+        let (user_id, username) = (user.id, user.username.clone());
+        let res = get_conn(&pool).and_then(|conn| {
+            crate::spotify_api::run_as_background_work(|| update_user_stats(&conn, user))
+        });
+        crate::metrics::record_update_outcome(&res);
+        if let Err(err) = &res {
+            error!("Error updating user {}: {}", username, err);
+        }
+
+        if results.send((user_id, res)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Tracks consecutive update failures for a user so that they aren't retried on every poll
+#[derive(Debug)]
+struct UpdateBackoff {
+    consecutive_failures: u32,
+    next_attempt_time: NaiveDateTime,
+}
+
+/// Returns how long to wait before retrying a user's update after it failed `consecutive_failures` times in a row.
+/// The delay starts at `base_delay` and doubles with each failure, up to `max_delay`.
+fn get_retry_delay(
+    base_delay: Duration,
+    max_delay: Duration,
+    consecutive_failures: u32,
+) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    (base_delay * (1 << exponent)).min(max_delay)
+}
+
+/// Queues updates for all users that are due for one and waits for all of them to finish.  Users whose updates have
+/// been failing are skipped until their backoff expires; failures are retried after the poll interval at first,
+/// backing off exponentially up to the minimum update interval.
+fn run_scheduled_updates(
+    pool: &Pool,
+    jobs: &Sender<User>,
+    results: &Receiver<(i64, Result<UpdateOutcome, Error>)>,
+    backoffs: &mut HashMap<i64, UpdateBackoff>,
+) -> Result<(), Error> {
+    let start_time = Utc::now().naive_utc();
+    let mut due_users = {
+        let conn = get_conn(pool)?;
+        db_util::get_users_due_for_update(&conn, start_time - CONF.min_update_interval)?
+    };
+    // Users that aren't due anymore have been updated some other way or deleted since they last failed
+    backoffs.retain(|user_id, _| due_users.iter().any(|user| user.id == *user_id));
+    due_users.retain(|user| match backoffs.get(&user.id) {
+        Some(backoff) => backoff.next_attempt_time <= start_time,
+        None => true,
+    });
+    SCHEDULER_STATUS.lock().unwrap().users_backing_off = backoffs.len();
+    if due_users.is_empty() {
+        return Ok(());
+    }
+
+    let user_count = due_users.len();
+    info!("Scheduling updates for {} users...", user_count);
+    {
+        let mut status = SCHEDULER_STATUS.lock().unwrap();
+        status.queue_depth = user_count;
+        status.cur_run_start_time = Some(start_time);
+    }
+
+    for user in due_users {
+        jobs.send(user)
+            .map_err(|_| -> Error { Error::Internal("Update workers have shut down".into()) })?;
+    }
+
+    let (mut users_updated, mut users_skipped, mut users_failed) = (0, 0, 0);
+    for _ in 0..user_count {
+        let (user_id, res) = results
+            .recv()
+            .map_err(|_| -> Error { Error::Internal("Update workers have shut down".into()) })?;
+        match res {
+            Ok(UpdateOutcome::Updated) => users_updated += 1,
+            Ok(_) => users_skipped += 1,
+            Err(_) => users_failed += 1,
+        }
+
+        if res.is_ok() {
+            backoffs.remove(&user_id);
+        } else {
+            let consecutive_failures = backoffs
+                .get(&user_id)
+                .map(|backoff| backoff.consecutive_failures + 1)
+                .unwrap_or(1);
+            let retry_delay = get_retry_delay(
+                CONF.update_scheduler_poll_interval,
+                CONF.min_update_interval,
+                consecutive_failures,
+            );
+            backoffs.insert(
+                user_id,
+                UpdateBackoff {
+                    consecutive_failures,
+                    next_attempt_time: Utc::now().naive_utc() + retry_delay,
+                },
+            );
+        }
+
+        let mut status = SCHEDULER_STATUS.lock().unwrap();
+        status.queue_depth -= 1;
+        status.users_backing_off = backoffs.len();
+    }
+
+    let run = SchedulerRun {
+        start_time,
+        end_time: Utc::now().naive_utc(),
+        users_updated,
+        users_skipped,
+        users_failed,
+    };
+    info!("Finished scheduled update run: {:?}", run);
+    let mut status = SCHEDULER_STATUS.lock().unwrap();
+    status.cur_run_start_time = None;
+    status.last_run = Some(run);
+
+    Ok(())
+}
+
+/// Starts the scheduler thread along with `CONF.update_concurrency` update worker threads.  Intended to be run as a
+/// launch fairing so that the database pool managed by Rocket is available.
+pub fn start(rocket: &Rocket) {
+    if !CONF.update_scheduler_enabled {
+        info!("Update scheduler is disabled; not starting it.");
+        return;
+    }
+
+    let pool: Pool = rocket
+        .state::<DbConnPool>()
+        .expect("Database pool must be attached before starting the update scheduler")
+        .0
+        .clone();
+    let concurrency = CONF.update_concurrency.max(1);
+    {
+        let mut status = SCHEDULER_STATUS.lock().unwrap();
+        status.enabled = true;
+        status.concurrency = concurrency;
+    }
+
+    let (job_tx, job_rx) = channel::unbounded();
+    let (res_tx, res_rx) = channel::unbounded();
+    for _ in 0..concurrency {
+        let (pool, job_rx, res_tx) = (pool.clone(), job_rx.clone(), res_tx.clone());
+        thread::spawn(move || run_update_worker(pool, job_rx, res_tx));
+    }
+
+    let poll_interval = CONF
+        .update_scheduler_poll_interval
+        .to_std()
+        .expect("Invalid update scheduler poll interval");
+    info!(
+        "Starting update scheduler with {} workers; polling every {:?}",
+        concurrency, poll_interval
+    );
+    thread::spawn(move || {
+        let mut backoffs = HashMap::new();
+        loop {
+            if let Err(err) = run_scheduled_updates(&pool, &job_tx, &res_rx, &mut backoffs) {
+                error!("Error running scheduled updates: {}", err);
+            }
+
+            thread::sleep(poll_interval);
+        }
+    });
+}
+
+#[test]
+fn update_retry_delay() {
+    let (base_delay, max_delay) = (Duration::minutes(1), Duration::hours(1));
+
+    assert_eq!(
+        get_retry_delay(base_delay, max_delay, 1),
+        Duration::minutes(1)
+    );
+    assert_eq!(
+        get_retry_delay(base_delay, max_delay, 2),
+        Duration::minutes(2)
+    );
+    assert_eq!(
+        get_retry_delay(base_delay, max_delay, 4),
+        Duration::minutes(8)
+    );
+    assert_eq!(
+        get_retry_delay(base_delay, max_delay, 7),
+        Duration::hours(1)
+    );
+    assert_eq!(
+        get_retry_delay(base_delay, max_delay, 100),
+        Duration::hours(1)
+    );
+}
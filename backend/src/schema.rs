@@ -20,10 +20,26 @@ table! {
 table! {
     artist_stats_history (id) {
         id -> Bigint,
-        spotify_id -> Varchar,
+        mapped_spotify_id -> Integer,
+        update_time -> Datetime,
         followers -> Unsigned<Bigint>,
         popularity -> Unsigned<Bigint>,
-        uri -> Text,
+    }
+}
+
+table! {
+    play_events (id) {
+        id -> Bigint,
+        user_id -> Bigint,
+        mapped_spotify_id -> Integer,
+        played_at -> Datetime,
+    }
+}
+
+table! {
+    snapshot_cutovers (name) {
+        name -> Varchar,
+        cutover_time -> Datetime,
     }
 }
 
@@ -34,6 +50,16 @@ table! {
     }
 }
 
+table! {
+    stats_updates (id) {
+        id -> Bigint,
+        user_id -> Bigint,
+        update_time -> Datetime,
+        artist_timeframes -> Unsigned<Tinyint>,
+        track_timeframes -> Unsigned<Tinyint>,
+    }
+}
+
 table! {
     tracks_artists (id) {
         id -> Integer,
@@ -56,7 +82,8 @@ table! {
 table! {
     track_stats_history (id) {
         id -> Bigint,
-        followers -> Unsigned<Bigint>,
+        mapped_spotify_id -> Integer,
+        update_time -> Datetime,
         popularity -> Unsigned<Bigint>,
         playcount -> Nullable<Unsigned<Bigint>>,
     }
@@ -71,20 +98,32 @@ table! {
         username -> Text,
         token -> Text,
         refresh_token -> Text,
+        refresh_failure_count -> Unsigned<Integer>,
+        last_refresh_error -> Nullable<Text>,
+        disabled -> Bool,
+        access_token_expires_at -> Nullable<Datetime>,
     }
 }
 
 joinable!(artist_rank_snapshots -> spotify_items (mapped_spotify_id));
 joinable!(artist_rank_snapshots -> users (user_id));
 joinable!(artists_genres -> spotify_items (artist_id));
+joinable!(artist_stats_history -> spotify_items (mapped_spotify_id));
+joinable!(play_events -> spotify_items (mapped_spotify_id));
+joinable!(play_events -> users (user_id));
+joinable!(stats_updates -> users (user_id));
 joinable!(track_rank_snapshots -> spotify_items (mapped_spotify_id));
 joinable!(track_rank_snapshots -> users (user_id));
+joinable!(track_stats_history -> spotify_items (mapped_spotify_id));
 
 allow_tables_to_appear_in_same_query!(
     artists_genres,
     artist_rank_snapshots,
     artist_stats_history,
+    play_events,
+    snapshot_cutovers,
     spotify_items,
+    stats_updates,
     tracks_artists,
     track_rank_snapshots,
     track_stats_history,
@@ -1,169 +1,444 @@
+use std::cell::Cell;
 use std::ops::Try;
+use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
-use crossbeam::channel;
 use diesel::prelude::*;
-use hashbrown::HashMap;
-use reqwest;
+use hashbrown::{HashMap, HashSet};
+use rand::Rng;
+use rayon::prelude::*;
+use reqwest::{
+    self,
+    blocking::{RequestBuilder, Response},
+    StatusCode,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::cache::{CachedEntry, MetadataCache, METADATA_CACHE};
 use crate::conf::CONF;
+use crate::error::Error;
+use crate::metrics;
 use crate::models::{
-    AccessTokenResponse, Artist, ArtistGenrePair, NewArtistHistoryEntry, NewTrackHistoryEntry,
-    SpotifyBatchArtistsResponse, SpotifyBatchTracksResponse, SpotifyResponse, StatsSnapshot,
-    TopArtistsResponse, TopTracksResponse, Track, TrackArtistPair, User, UserProfile,
+    AccessTokenResponse, Artist, ArtistGenrePair, HasSpotifyId, NewArtistHistoryEntry,
+    NewArtistStatsHistoryEntry, NewStatsUpdate, NewTrackHistoryEntry, NewTrackStatsHistoryEntry,
+    OAuthErrorResponse, RecentlyPlayedResponse, SpotifyBatchArtistsResponse,
+    SpotifyBatchTracksResponse, SpotifyResponse, StatsSnapshot, TopArtistsResponse,
+    TopTracksResponse, Track, TrackArtistPair, TrackPlay, TrackSearchResponse, User, UserProfile,
 };
+use crate::spotify_token::BACKGROUND_TOKEN_DATA;
 use crate::DbConn;
 
-const SPOTIFY_USER_RECENTLY_PLAYED_URL: &str =
-    "https://api.spotify.com/v1/me/player/recently-played";
-const SPOTIFY_USER_PROFILE_INFO_URL: &str = "https://api.spotify.com/v1/me";
-const SPOTIFY_BATCH_TRACKS_URL: &str = "https://api.spotify.com/v1/tracks";
-const SPOTIFY_BATCH_ARTISTS_URL: &str = "https://api.spotify.com/v1/artists";
-const SPOTIFY_APP_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
-const ENTITY_FETCH_COUNT: usize = 50;
+// Paths are relative to `CONF.spotify_api_base_url` or `CONF.spotify_accounts_base_url`
+const SPOTIFY_USER_RECENTLY_PLAYED_PATH: &str = "/me/player/recently-played";
+const SPOTIFY_USER_PROFILE_INFO_PATH: &str = "/me";
+const SPOTIFY_SEARCH_PATH: &str = "/search";
+const SPOTIFY_BATCH_TRACKS_PATH: &str = "/tracks";
+const SPOTIFY_BATCH_ARTISTS_PATH: &str = "/artists";
+pub const SPOTIFY_TOKEN_PATH: &str = "/api/token";
+/// Maximum number of items that can be fetched in a single request to the top artists/tracks endpoints
+const MAX_TOP_ENTITIES_PAGE_SIZE: usize = 50;
+const RECENTLY_PLAYED_PAGE_SIZE: usize = 50;
+/// Upper bound on the number of recently played pages fetched in a single update, in case Spotify keeps handing
+/// us cursors
+const MAX_RECENTLY_PLAYED_PAGES: usize = 20;
+
+/// How long idle connections to the Spotify API are kept open for re-use
+const HTTP_POOL_IDLE_TIMEOUT_SECONDS: u64 = 90;
+
+lazy_static! {
+    /// HTTP client shared by everything that talks to the Spotify API so that connections are kept alive and re-used
+    /// rather than re-doing TLS handshakes for every request.
+    pub static ref HTTP_CLIENT: reqwest::blocking::Client = reqwest::blocking::Client::builder()
+        .user_agent(CONF.http_user_agent.as_str())
+        .timeout(
+            CONF.spotify_request_timeout
+                .to_std()
+                .expect("Invalid Spotify request timeout")
+        )
+        .connect_timeout(
+            CONF.spotify_connect_timeout
+                .to_std()
+                .expect("Invalid Spotify connect timeout")
+        )
+        .pool_idle_timeout(Duration::from_secs(HTTP_POOL_IDLE_TIMEOUT_SECONDS))
+        .pool_max_idle_per_host(
+            CONF.spotify_request_concurrency + CONF.spotify_background_request_concurrency
+        )
+        .build()
+        .expect("Failed to build HTTP client");
+
+    /// Thread pool used to fan out concurrent requests to the Spotify API while serving requests.  Its size bounds the
+    /// number of in-flight interactive requests across the whole process.
+    static ref SPOTIFY_REQUEST_POOL: rayon::ThreadPool = rayon::ThreadPoolBuilder::new()
+        .num_threads(CONF.spotify_request_concurrency.max(1))
+        .thread_name(|i| format!("spotify-request-{}", i))
+        .build()
+        .expect("Failed to build Spotify request thread pool");
+
+    /// Thread pool used for requests made by background work; see `run_as_background_work`.  Retries sleep on the
+    /// thread making the request, so keeping this separate means that a scheduler run waiting out rate limits can't
+    /// starve interactive requests of threads, no matter how many users are being updated at once.
+    static ref SPOTIFY_BACKGROUND_POOL: rayon::ThreadPool = rayon::ThreadPoolBuilder::new()
+        .num_threads(CONF.spotify_background_request_concurrency.max(1))
+        .thread_name(|i| format!("spotify-background-{}", i))
+        // Rayon aborts the process when a job spawned onto the pool panics unless a handler is set
+        .panic_handler(|_| error!("Background Spotify request job panicked"))
+        .build()
+        .expect("Failed to build Spotify background request thread pool");
+}
+
+thread_local! {
+    static IS_BACKGROUND_WORK: Cell<bool> = Cell::new(false);
+}
+
+/// Runs `f` on the current thread, fanning out any requests it makes to the Spotify API on the background request pool
+/// rather than the one used for serving requests.
+pub fn run_as_background_work<R>(f: impl FnOnce() -> R) -> R {
+    struct ResetGuard(bool);
+
+    impl Drop for ResetGuard {
+        fn drop(&mut self) {
+            let was_background_work = self.0;
+            IS_BACKGROUND_WORK
+                .with(|is_background_work| is_background_work.set(was_background_work));
+        }
+    }
+
+    let _guard =
+        ResetGuard(IS_BACKGROUND_WORK.with(|is_background_work| is_background_work.replace(true)));
+    f()
+}
+
+/// Runs `f`, which fans out requests to the Spotify API with rayon, on the pool matching the kind of work being done.
+/// Fan-outs nested inside of another one stay on the pool that they're already running on.
+fn fan_out<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+    if SPOTIFY_REQUEST_POOL.current_thread_index().is_some()
+        || SPOTIFY_BACKGROUND_POOL.current_thread_index().is_some()
+    {
+        f()
+    } else if IS_BACKGROUND_WORK.with(Cell::get) {
+        SPOTIFY_BACKGROUND_POOL.install(f)
+    } else {
+        SPOTIFY_REQUEST_POOL.install(f)
+    }
+}
+
+/// Base delay for exponential backoff when retrying failed requests to the Spotify API
+const RETRY_BASE_DELAY_MS: u64 = 500;
+/// Maximum delay between retries when backing off, not including delays requested via `Retry-After`
+const RETRY_MAX_DELAY_MS: u64 = 30 * 1000;
+
+/// Strips the query string from a URL so that it can be used to identify the endpoint in logs
+fn get_endpoint_name(url: &str) -> &str {
+    url.split('?').next().unwrap_or(url)
+}
+
+/// Endpoint name without the base URL, such as `/me/top/artists`, used to label metrics
+fn get_endpoint_label(endpoint_name: &str) -> &str {
+    endpoint_name
+        .trim_start_matches(CONF.spotify_api_base_url.as_str())
+        .trim_start_matches(CONF.spotify_accounts_base_url.as_str())
+}
+
+/// Computes the delay before the retry following `attempt` failed attempts, doubling each time and randomized to
+/// avoid many requests retrying in lockstep.
+fn get_backoff_delay(attempt: usize) -> Duration {
+    let max_delay_ms = RETRY_BASE_DELAY_MS
+        .saturating_mul(1 << attempt.min(16))
+        .min(RETRY_MAX_DELAY_MS);
+    let jitter_factor: f64 = rand::thread_rng().gen_range(0.5, 1.0);
+    Duration::from_millis((max_delay_ms as f64 * jitter_factor) as u64)
+}
+
+fn get_retry_after_delay(res: &Response) -> Option<Duration> {
+    res.headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Sends a request to the Spotify API, retrying it if Spotify responds with a 429 (rate limited) or 5xx status code.
+/// Rate limited requests are retried after the delay provided in the `Retry-After` header; everything else is retried
+/// with jittered exponential backoff.  We give up once `CONF.spotify_max_retries` retries have been made or the total
+/// time spent waiting would exceed `CONF.spotify_retry_budget`.
+///
+/// `build_request` is called once for each attempt since request builders can't be re-used.  Responses with any other
+/// status code are returned as-is and must be checked by the caller.
+pub fn send_with_retry<F: Fn() -> RequestBuilder>(
+    url: &str,
+    build_request: F,
+) -> Result<Response, Error> {
+    let endpoint_name = get_endpoint_name(url);
+    let endpoint_label = get_endpoint_label(endpoint_name);
+    let retry_budget = CONF
+        .spotify_retry_budget
+        .to_std()
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    let mut total_delay = Duration::from_secs(0);
+    let mut attempt = 0;
 
-fn get_top_entities_url(entity_type: &str, timeframe: &str) -> String {
+    loop {
+        let request_start = Instant::now();
+        let res = build_request().send();
+        metrics::SPOTIFY_API_REQUEST_DURATION.observe(&[endpoint_label], request_start.elapsed());
+        let res = res.map_err(|err| -> Error {
+            metrics::SPOTIFY_API_REQUESTS.inc(&[endpoint_label, "error"]);
+            error!(
+                "Error communicating with Spotify API endpoint {}: {:?}",
+                endpoint_name, err
+            );
+            Error::http("Error communicating with the Spotify API", err)
+        })?;
+
+        let status = res.status();
+        metrics::SPOTIFY_API_REQUESTS.inc(&[endpoint_label, status.as_str()]);
+        let delay = if status == StatusCode::TOO_MANY_REQUESTS {
+            let delay = get_retry_after_delay(&res).unwrap_or_else(|| get_backoff_delay(attempt));
+            warn!(
+                "Rate limited by Spotify API endpoint {}; Retry-After: {:?}",
+                endpoint_name, delay
+            );
+            delay
+        } else if status.is_server_error() {
+            warn!(
+                "Got status code {} from Spotify API endpoint {}",
+                status, endpoint_name
+            );
+            get_backoff_delay(attempt)
+        } else {
+            return Ok(res);
+        };
+
+        if attempt >= CONF.spotify_max_retries || total_delay + delay > retry_budget {
+            metrics::SPOTIFY_RETRIES_EXHAUSTED.inc(&[endpoint_label]);
+            error!(
+                "Giving up on request to Spotify API endpoint {} after {} retries ({:?} spent waiting); last status code was {}",
+                endpoint_name, attempt, total_delay, status
+            );
+            return Err(if status == StatusCode::TOO_MANY_REQUESTS {
+                Error::RateLimited {
+                    retry_after: Some(delay),
+                }
+            } else {
+                Error::spotify_api(Some(status.as_u16()), "Got bad response from Spotify API")
+            });
+        }
+
+        metrics::SPOTIFY_RETRIES.inc(&[endpoint_label]);
+        info!(
+            "Retrying request to Spotify API endpoint {} in {:?} (retry {}/{})",
+            endpoint_name,
+            delay,
+            attempt + 1,
+            CONF.spotify_max_retries
+        );
+        thread::sleep(delay);
+        total_delay += delay;
+        attempt += 1;
+    }
+}
+
+fn get_top_entities_url(entity_type: &str, timeframe: &str, limit: usize, offset: usize) -> String {
     format!(
-        "https://api.spotify.com/v1/me/top/{}?limit={}&time_range={}_term",
-        entity_type, ENTITY_FETCH_COUNT, timeframe
+        "{}/me/top/{}?limit={}&offset={}&time_range={}_term",
+        CONF.spotify_api_base_url, entity_type, limit, offset, timeframe
     )
 }
 
 pub fn spotify_user_api_request<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone>(
     url: &str,
     token: &str,
-) -> Result<T, String> {
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .get(url)
-        .bearer_auth(token)
-        .send()
-        .map_err(|err| -> String {
-            error!("Error fetching user data from Spotify API: {:?}", err);
-            "Error requesting latest user data from the Spotify API".into()
-        })?;
+) -> Result<T, Error> {
+    let res = send_with_retry(url, || HTTP_CLIENT.get(url).bearer_auth(token))?;
 
     res.json::<SpotifyResponse<T>>()
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!(
                 "Error parsing user data response from Spotify API: {:?}",
                 err
             );
-            "Error parsing user data response from Spotify API".into()
+            Error::http("Error parsing user data response from Spotify API", err)
         })?
         .into_result()
 }
 
-pub fn get_user_profile_info(token: &str) -> Result<UserProfile, String> {
-    spotify_user_api_request(SPOTIFY_USER_PROFILE_INFO_URL, token)
+pub fn get_user_profile_info(token: &str) -> Result<UserProfile, Error> {
+    spotify_user_api_request(
+        &CONF.get_spotify_api_url(SPOTIFY_USER_PROFILE_INFO_PATH),
+        token,
+    )
 }
 
 pub fn spotify_server_api_request<T: for<'de> Deserialize<'de> + std::fmt::Debug + Clone>(
     url: &str,
     params: HashMap<&str, &str>,
-) -> Result<T, String> {
-    let client = reqwest::blocking::Client::new();
-
+) -> Result<T, Error> {
     info!("Hitting Spotify API at URL {}, params: {:?}", url, params);
-    let res = client
-        .post(url)
-        .header("Authorization", CONF.get_authorization_header_content())
-        .form(&params)
-        .send()
-        .map_err(|err| -> String {
-            error!("Error communicating with Spotify API: {:?}", err);
-            "Error communicating with from the Spotify API".into()
-        })?;
+    let res = send_with_retry(url, || {
+        HTTP_CLIENT
+            .post(url)
+            .header("Authorization", CONF.get_authorization_header_content())
+            .form(&params)
+    })?;
 
-    if res.status() != reqwest::StatusCode::OK {
-        error!("Got bad status code of {} from Spotify API", res.status());
-        return Err("Got bad response from Spotify API".into());
+    let status = res.status();
+    if status != reqwest::StatusCode::OK {
+        // The accounts service explains why it rejected the request with an OAuth error code such as
+        // `invalid_grant`, which is kept on the error so that callers can tell rejections apart
+        let oauth_error = res.json::<OAuthErrorResponse>().ok();
+        error!(
+            "Got bad status code of {} from Spotify API: {:?}",
+            status, oauth_error
+        );
+        let (message, oauth_error) = match oauth_error {
+            Some(OAuthErrorResponse {
+                error,
+                error_description: Some(description),
+            }) => (format!("{}: {}", error, description), Some(error)),
+            Some(OAuthErrorResponse { error, .. }) => (error.clone(), Some(error)),
+            None => ("Got bad response from Spotify API".into(), None),
+        };
+        return Err(Error::SpotifyApi {
+            status: Some(status.as_u16()),
+            message,
+            oauth_error,
+        });
     }
 
     res.json::<SpotifyResponse<T>>()
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!("Error decoding response from Spotify API: {:?}.", err,);
-            "Error decoding response from Spotify API".into()
+            Error::http("Error decoding response from Spotify API", err)
         })?
         .into_result()
 }
 
-pub fn fetch_auth_token() -> Result<AccessTokenResponse, String> {
+pub fn fetch_auth_token() -> Result<AccessTokenResponse, Error> {
     let mut params = HashMap::new();
     params.insert("grant_type", "client_credentials");
 
-    spotify_server_api_request(SPOTIFY_APP_TOKEN_URL, params)
+    spotify_server_api_request(&CONF.get_spotify_accounts_url(SPOTIFY_TOKEN_PATH), params)
 }
 
-pub fn refresh_user_token(refresh_token: &str) -> Result<String, String> {
+/// Exchanges a user's refresh token for a new access token.  The response may contain a new refresh token if Spotify
+/// rotated it, in which case it must be stored in place of the old one.
+pub fn refresh_user_token(refresh_token: &str) -> Result<AccessTokenResponse, Error> {
     let mut params = HashMap::new();
     params.insert("grant_type", "refresh_token");
     params.insert("refresh_token", refresh_token);
 
-    let res: AccessTokenResponse = spotify_server_api_request(SPOTIFY_APP_TOKEN_URL, params)?;
-    Ok(res.access_token)
-}
-
-pub fn fetch_cur_stats(user: &User) -> Result<Option<StatsSnapshot>, String> {
-    // Use the user's token to fetch their current stats
-    let (tx, rx) = channel::unbounded::<(
-        &'static str,
-        &'static str,
-        Result<reqwest::blocking::Response, String>,
-    )>();
-
-    // Create threads for each of the inner requests (we have to make 6; one for each of the three
-    // timeframes, and then that multiplied by each of the two entities (tracks and artists)).
-    info!("Kicking off 6 API requests on separate threads...");
-    for entity_type in &["tracks", "artists"] {
-        for timeframe in &["short", "medium", "long"] {
-            let token = user.token.clone();
-            let tx = tx.clone();
-
-            thread::spawn(move || {
-                let client = reqwest::blocking::Client::new();
-                let res: Result<reqwest::blocking::Response, String> = client
-                    .get(&get_top_entities_url(entity_type, timeframe))
-                    .bearer_auth(token)
-                    .send()
-                    .map_err(|_err| -> String {
-                        "Error requesting latest user stats from the Spotify API".into()
-                    });
-
-                tx.send((entity_type, timeframe, res))
-            });
+    let res =
+        spotify_server_api_request(&CONF.get_spotify_accounts_url(SPOTIFY_TOKEN_PATH), params);
+    metrics::record_token_refresh("user", &res);
+    res
+}
+
+/// Fetches the user's top `CONF.top_entity_fetch_depth` entities of the given type for the given timeframe.  If that's
+/// more than can be fetched in one request, follow-up pages are fetched using `offset`.  Entities are returned in
+/// ranked order.
+fn fetch_top_entities<R: for<'de> Deserialize<'de>, T: HasSpotifyId>(
+    token: &str,
+    entity_type: &str,
+    timeframe: &str,
+    get_items: fn(R) -> Vec<T>,
+) -> Result<Vec<T>, Error> {
+    let depth = CONF.top_entity_fetch_depth;
+    let mut entities: Vec<T> = Vec::with_capacity(depth);
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    let mut offset = 0;
+
+    while offset < depth {
+        let limit = (depth - offset).min(MAX_TOP_ENTITIES_PAGE_SIZE);
+        let url = get_top_entities_url(entity_type, timeframe, limit, offset);
+        let res = send_with_retry(&url, || HTTP_CLIENT.get(&url).bearer_auth(token))?;
+        if !res.status().is_success() {
+            error!(
+                "Got bad status code of {} when fetching top {}",
+                res.status(),
+                entity_type
+            );
+            return Err(Error::spotify_api(
+                Some(res.status().as_u16()),
+                "Error requesting latest user stats from the Spotify API",
+            ));
+        }
+
+        let page = get_items(res.json().map_err(|err| -> Error {
+            error!("Error parsing top {} response: {:?}", entity_type, err);
+            Error::http("Error parsing response from Spotify", err)
+        })?);
+        let page_len = page.len();
+        offset += page_len;
+
+        // Pages can overlap if the user's top items shift between requests, so skip anything we've already seen to
+        // keep rankings contiguous
+        for entity in page {
+            if seen_ids.insert(entity.get_spotify_id().to_string()) {
+                entities.push(entity);
+            }
+        }
+
+        // Spotify returns fewer items than requested once we've reached the end of the user's top items
+        if page_len < limit {
+            break;
         }
     }
 
-    let mut stats_snapshot = StatsSnapshot::new(Utc::now().naive_utc());
+    Ok(entities)
+}
 
-    // Wait for all 6 requests to return back and then
-    info!("Waiting for all 6 inner stats requests to return...");
-    for _ in 0..6 {
-        match rx.recv().unwrap() {
-            ("tracks", timeframe, res) => {
-                let parsed_res: TopTracksResponse = res?.json().map_err(|err| -> String {
-                    error!("Error parsing top tracks response: {:?}", err);
-                    "Error parsing response from Spotify".into()
-                })?;
+enum TopEntities {
+    Tracks(Vec<Track>),
+    Artists(Vec<Artist>),
+}
 
-                for top_track in parsed_res.items.into_iter() {
-                    stats_snapshot.tracks.add_item(timeframe, top_track);
-                }
-            }
-            ("artists", timeframe, res) => {
-                let parsed_res: TopArtistsResponse = res?.json().map_err(|err| -> String {
-                    error!("Error parsing top artists response: {:?}", err);
-                    "Error parsing response from Spotify".into()
-                })?;
+pub fn fetch_cur_stats(user: &User) -> Result<Option<StatsSnapshot>, Error> {
+    // We have to make 6 requests (more if paginating); one for each of the three timeframes, and then that multiplied
+    // by each of the two entities (tracks and artists).  They're fanned out over the shared Spotify request pool.
+    let requests: Vec<(&'static str, &'static str)> = ["tracks", "artists"]
+        .iter()
+        .flat_map(|entity_type| {
+            ["short", "medium", "long"]
+                .iter()
+                .map(move |timeframe| (*entity_type, *timeframe))
+        })
+        .collect();
 
-                for top_artist in parsed_res.items.into_iter() {
-                    stats_snapshot.artists.add_item(timeframe, top_artist);
-                }
-            }
-            _ => unreachable!(),
+    info!("Fetching top tracks and artists for all timeframes...");
+    let results: Vec<(&'static str, Result<TopEntities, Error>)> = fan_out(|| {
+        requests
+            .into_par_iter()
+            .map(|(entity_type, timeframe)| {
+                let res = match entity_type {
+                    "tracks" => fetch_top_entities(
+                        &user.token,
+                        entity_type,
+                        timeframe,
+                        |res: TopTracksResponse| res.items,
+                    )
+                    .map(TopEntities::Tracks),
+                    _ => fetch_top_entities(
+                        &user.token,
+                        entity_type,
+                        timeframe,
+                        |res: TopArtistsResponse| res.items,
+                    )
+                    .map(TopEntities::Artists),
+                };
+
+                (timeframe, res)
+            })
+            .collect()
+    });
+
+    let mut stats_snapshot = StatsSnapshot::new(Utc::now().naive_utc());
+    for (timeframe, res) in results {
+        match res? {
+            TopEntities::Tracks(tracks) => stats_snapshot.tracks.set(timeframe, tracks),
+            TopEntities::Artists(artists) => stats_snapshot.artists.set(timeframe, artists),
         }
     }
 
@@ -183,14 +458,27 @@ fn map_timeframe_to_timeframe_id(timeframe: &str) -> u8 {
 }
 
 /// For each track and artist timeframe, store a row in the `track_rank_snapshots` and `artist_rank_snapshots`
-/// tables respectively
-pub fn store_stats_snapshot(
+/// tables respectively.  Timeframes whose rankings are identical to the user's previous update are skipped; the
+/// `stats_updates` entry created for the update records which timeframes were stored.
+///
+/// The whole snapshot is stored in a single transaction; if any part of it fails to be stored, none of it is.
+pub fn store_stats_snapshot(conn: &DbConn, user: &User, stats: StatsSnapshot) -> Result<(), Error> {
+    crate::db_util::with_transaction(conn, || store_stats_snapshot_inner(conn, user, stats))
+}
+
+fn store_stats_snapshot_inner(
     conn: &DbConn,
     user: &User,
     stats: StatsSnapshot,
-) -> Result<(), String> {
+) -> Result<(), Error> {
     let update_time = stats.last_update_time;
 
+    let stats_updates = crate::db_util::get_stats_updates(conn, user)?;
+    let prev_artist_rankings =
+        crate::db_util::get_current_artist_rankings(conn, user, &stats_updates)?;
+    let prev_track_rankings =
+        crate::db_util::get_current_track_rankings(conn, user, &stats_updates)?;
+
     let genres_by_artist_id: HashMap<String, Vec<String>> = stats
         .artists
         .iter()
@@ -209,33 +497,75 @@ pub fn store_stats_snapshot(
     let mapped_artist_spotify_ids =
         crate::db_util::retrieve_mapped_spotify_ids(conn, genres_by_artist_id.keys())?;
 
+    // Record global follower + popularity stats for all artists that we have full metadata for.  Artists included in
+    // track metadata are simplified and don't include these fields.
+    let artist_stats_history_entries: Vec<NewArtistStatsHistoryEntry> = stats
+        .artists
+        .iter()
+        .flat_map(|(_artist_timeframe, artists)| artists.iter())
+        .filter_map(|artist| match (&artist.followers, artist.popularity) {
+            (Some(followers), Some(popularity)) => Some((
+                mapped_artist_spotify_ids[&artist.id],
+                NewArtistStatsHistoryEntry {
+                    mapped_spotify_id: mapped_artist_spotify_ids[&artist.id],
+                    update_time,
+                    followers: followers.total as u64,
+                    popularity: popularity as u64,
+                },
+            )),
+            _ => None,
+        })
+        // The same artist often shows up in multiple timeframes
+        .collect::<HashMap<_, _>>()
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect();
+    diesel::insert_or_ignore_into(crate::schema::artist_stats_history::table)
+        .values(&artist_stats_history_entries)
+        .execute(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error inserting artist stats history: {:?}", err);
+            Error::database("Error inserting artist stats history into database", err)
+        })?;
+
+    // Only store rankings for timeframes that changed since the last update
+    let mut artist_timeframes: u8 = 0;
     let artist_entries: Vec<NewArtistHistoryEntry> = stats
         .artists
         .into_iter()
-        .flat_map(|(artist_timeframe, artists)| {
-            artists
-                .into_iter()
-                .enumerate()
-                .map(move |(artist_ranking, artist)| (artist_timeframe, artist_ranking, artist.id))
-                .map(|(artist_timeframe, artist_ranking, artist_spotify_id)| {
-                    NewArtistHistoryEntry {
-                        user_id: user.id,
-                        mapped_spotify_id: mapped_artist_spotify_ids[&artist_spotify_id],
-                        update_time,
-                        timeframe: map_timeframe_to_timeframe_id(&artist_timeframe),
-                        ranking: artist_ranking as u16,
-                    }
-                })
+        .filter_map(|(artist_timeframe, artists)| {
+            let timeframe_id = map_timeframe_to_timeframe_id(&artist_timeframe);
+            let mapped_ids: Vec<i32> = artists
+                .iter()
+                .map(|artist| mapped_artist_spotify_ids[&artist.id])
+                .collect();
+            if mapped_ids == prev_artist_rankings[timeframe_id as usize] {
+                return None;
+            }
+
+            artist_timeframes |= 1 << timeframe_id;
+            Some(mapped_ids.into_iter().enumerate().map(
+                move |(artist_ranking, mapped_spotify_id)| NewArtistHistoryEntry {
+                    user_id: user.id,
+                    mapped_spotify_id,
+                    update_time,
+                    timeframe: timeframe_id,
+                    ranking: artist_ranking as u16,
+                },
+            ))
         })
+        .flatten()
         .collect();
 
-    diesel::insert_into(crate::schema::artist_rank_snapshots::table)
-        .values(&artist_entries)
-        .execute(&conn.0)
-        .map_err(|err| -> String {
-            println!("Error inserting row: {:?}", err);
-            "Error inserting user into database".into()
-        })?;
+    if !artist_entries.is_empty() {
+        diesel::insert_into(crate::schema::artist_rank_snapshots::table)
+            .values(&artist_entries)
+            .execute(&conn.0)
+            .map_err(|err| -> Error {
+                error!("Error inserting artist rank snapshot rows: {:?}", err);
+                Error::database("Error inserting artist rankings into database", err)
+            })?;
+    }
 
     let track_spotify_ids: Vec<String> = stats
         .tracks
@@ -245,6 +575,34 @@ pub fn store_stats_snapshot(
     let mapped_track_spotify_ids =
         crate::db_util::retrieve_mapped_spotify_ids(conn, track_spotify_ids.iter())?;
 
+    // Record global popularity stats for all tracks
+    let track_stats_history_entries: Vec<NewTrackStatsHistoryEntry> = stats
+        .tracks
+        .iter()
+        .flat_map(|(_track_timeframe, tracks)| tracks.iter())
+        .map(|track| {
+            (
+                mapped_track_spotify_ids[&track.id],
+                NewTrackStatsHistoryEntry {
+                    mapped_spotify_id: mapped_track_spotify_ids[&track.id],
+                    update_time,
+                    popularity: track.popularity as u64,
+                    playcount: None,
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>()
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect();
+    diesel::insert_or_ignore_into(crate::schema::track_stats_history::table)
+        .values(&track_stats_history_entries)
+        .execute(&conn.0)
+        .map_err(|err| -> Error {
+            error!("Error inserting track stats history: {:?}", err);
+            Error::database("Error inserting track stats history into database", err)
+        })?;
+
     // Create track/artist mapping entries for each (track, artist) pair
     let track_artist_pairs: Vec<TrackArtistPair> = stats
         .tracks
@@ -267,9 +625,9 @@ pub fn store_stats_snapshot(
     diesel::insert_or_ignore_into(crate::schema::tracks_artists::table)
         .values(&track_artist_pairs)
         .execute(&conn.0)
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!("Error inserting track/artist mappings: {:?}", err);
-            "Error inserting track/artist metadata into database".into()
+            Error::database("Error inserting track/artist metadata into database", err)
         })?;
 
     // Create artist/genre mapping entries for each (artist, genre) pair
@@ -300,37 +658,64 @@ pub fn store_stats_snapshot(
                 .values(&artist_genre_pairs)
                 .execute(&conn.0)
         })
-        .map_err(|err| -> String {
+        .map_err(|err| -> Error {
             error!("Error inserting artist/genre mappings: {:?}", err);
-            "Error inserting artist/genre mappings into database".into()
+            Error::database("Error inserting artist/genre mappings into database", err)
         })?;
 
+    let mut track_timeframes: u8 = 0;
     let track_entries: Vec<NewTrackHistoryEntry> = stats
         .tracks
         .into_iter()
-        .flat_map(|(track_timeframe, tracks)| {
-            tracks
-                .into_iter()
-                .enumerate()
-                .map(move |(track_ranking, track)| (track_timeframe, track_ranking, track.id))
-                .map(
-                    |(track_timeframe, track_ranking, track_spotify_id)| NewTrackHistoryEntry {
-                        user_id: user.id,
-                        mapped_spotify_id: mapped_track_spotify_ids[&track_spotify_id],
-                        update_time,
-                        timeframe: map_timeframe_to_timeframe_id(&track_timeframe),
-                        ranking: track_ranking as u16,
-                    },
-                )
+        .filter_map(|(track_timeframe, tracks)| {
+            let timeframe_id = map_timeframe_to_timeframe_id(&track_timeframe);
+            let mapped_ids: Vec<i32> = tracks
+                .iter()
+                .map(|track| mapped_track_spotify_ids[&track.id])
+                .collect();
+            if mapped_ids == prev_track_rankings[timeframe_id as usize] {
+                return None;
+            }
+
+            track_timeframes |= 1 << timeframe_id;
+            Some(mapped_ids.into_iter().enumerate().map(
+                move |(track_ranking, mapped_spotify_id)| NewTrackHistoryEntry {
+                    user_id: user.id,
+                    mapped_spotify_id,
+                    update_time,
+                    timeframe: timeframe_id,
+                    ranking: track_ranking as u16,
+                },
+            ))
         })
+        .flatten()
         .collect();
 
-    diesel::insert_into(crate::schema::track_rank_snapshots::table)
-        .values(&track_entries)
+    if !track_entries.is_empty() {
+        diesel::insert_into(crate::schema::track_rank_snapshots::table)
+            .values(&track_entries)
+            .execute(&conn.0)
+            .map_err(|err| -> Error {
+                error!("Error inserting track rank snapshot rows: {:?}", err);
+                Error::database("Error inserting track rankings into database", err)
+            })?;
+    }
+
+    info!(
+        "Storing update for user {}; changed timeframes bitmasks: artists={:03b}, tracks={:03b}",
+        user.username, artist_timeframes, track_timeframes
+    );
+    diesel::insert_into(crate::schema::stats_updates::table)
+        .values(&NewStatsUpdate {
+            user_id: user.id,
+            update_time,
+            artist_timeframes,
+            track_timeframes,
+        })
         .execute(&conn.0)
-        .map_err(|err| -> String {
-            error!("Error inserting row: {:?}", err);
-            "Error inserting user into database".into()
+        .map_err(|err| -> Error {
+            error!("Error inserting stats update: {:?}", err);
+            Error::database("Error inserting stats update into database", err)
         })?;
 
     // Update the user to have a last update time that matches all of the new updates
@@ -346,94 +731,391 @@ pub fn store_stats_snapshot(
     Ok(())
 }
 
+/// Pulls the user's recently played tracks from the Spotify API and stores them as individual play events.  If we've
+/// already ingested plays for the user, pages forward from the most recent stored play using the `after` cursor;
+/// otherwise pages backwards from now using the `before` cursor.  Plays are de-duplicated on their `played_at` time.
+///
+/// Returns the number of new play events that were stored.
+pub fn ingest_recently_played(conn: &DbConn, user: &User) -> Result<usize, Error> {
+    let latest_played_at = crate::db_util::get_latest_play_event_time(conn, user)?;
+    let (cursor_name, mut cursor) = match latest_played_at {
+        Some(latest_played_at) => (
+            "after",
+            Some(latest_played_at.timestamp_millis().to_string()),
+        ),
+        None => ("before", None),
+    };
+
+    let recently_played_url = CONF.get_spotify_api_url(SPOTIFY_USER_RECENTLY_PLAYED_PATH);
+    let mut play_history = Vec::new();
+    for _ in 0..MAX_RECENTLY_PLAYED_PAGES {
+        let url = match &cursor {
+            Some(cursor) => format!(
+                "{}?limit={}&{}={}",
+                recently_played_url, RECENTLY_PLAYED_PAGE_SIZE, cursor_name, cursor
+            ),
+            None => format!(
+                "{}?limit={}",
+                recently_played_url, RECENTLY_PLAYED_PAGE_SIZE
+            ),
+        };
+        let res: RecentlyPlayedResponse = spotify_user_api_request(&url, &user.token)?;
+        let page_is_empty = res.items.is_empty();
+        play_history.extend(res.items);
+
+        let next_cursor = res.cursors.and_then(|cursors| match cursor_name {
+            "after" => cursors.after,
+            _ => cursors.before,
+        });
+        match next_cursor {
+            Some(next_cursor) if !page_is_empty && res.next.is_some() => cursor = Some(next_cursor),
+            _ => break,
+        }
+    }
+    info!(
+        "Fetched {} recently played tracks for user {}",
+        play_history.len(),
+        user.username
+    );
+
+    // Local files don't have a Spotify ID, so there's nothing we can map them to
+    let plays: Vec<TrackPlay> = play_history
+        .into_iter()
+        .filter_map(|item| {
+            let track_spotify_id = item.track.id?;
+            Some(TrackPlay {
+                track_spotify_id,
+                artist_spotify_ids: item
+                    .track
+                    .artists
+                    .into_iter()
+                    .map(|artist| artist.id)
+                    .collect(),
+                played_at: item.played_at.naive_utc(),
+            })
+        })
+        .collect();
+
+    crate::db_util::store_play_events(conn, user, &plays)
+}
+
+/// Searches for the Spotify ID of each of the provided `(artist name, track name)` pairs, returning `None` for tracks
+/// that couldn't be found.  Results are cached.  Tracks that couldn't be found are searched for again once their cached
+/// miss is older than `cache.track_search_misses_ttl_seconds` since they may have been added to Spotify since.
+pub fn search_track_ids(
+    spotify_access_token: &str,
+    queries: &[(&str, &str)],
+) -> Result<Vec<Option<String>>, Error> {
+    let cache_keys: Vec<String> = queries
+        .iter()
+        .map(|(artist_name, track_name)| format!("{}\u{1f}{}", artist_name, track_name))
+        .collect();
+    let cache_key_refs: Vec<&str> = cache_keys.iter().map(String::as_str).collect();
+    let cached: Vec<Option<Option<String>>> = crate::cache::get_entries::<Option<String>>(
+        &**METADATA_CACHE,
+        &CONF.track_searches_cache_hash_name,
+        &cache_key_refs,
+    )?
+    .into_iter()
+    .map(|entry| {
+        let entry = entry?;
+        let is_stale = entry.is_stale(CONF.track_search_misses_cache_ttl);
+        // Misses used to be cached as empty strings with no fetch time
+        match entry.value.filter(|track_id| !track_id.is_empty()) {
+            None if is_stale => None,
+            track_id => Some(track_id),
+        }
+    })
+    .collect();
+
+    let missing_indices: Vec<usize> = cached
+        .iter()
+        .enumerate()
+        .filter(|(_, cached)| cached.is_none())
+        .map(|(i, _)| i)
+        .collect();
+    let searched: Vec<Option<String>> = fan_out(|| {
+        missing_indices
+            .par_iter()
+            .map(|&i| -> Result<Option<String>, Error> {
+                let (artist_name, track_name) = queries[i];
+                // Search queries have no way of escaping quotes within a quoted term
+                let query = format!(
+                    "track:\"{}\" artist:\"{}\"",
+                    track_name.replace('"', " "),
+                    artist_name.replace('"', " ")
+                );
+                let url = reqwest::Url::parse_with_params(
+                    &CONF.get_spotify_api_url(SPOTIFY_SEARCH_PATH),
+                    &[("q", query.as_str()), ("type", "track"), ("limit", "1")],
+                )
+                .map_err(|err| -> Error {
+                    error!("Error building track search URL: {:?}", err);
+                    Error::Internal("Error building track search URL".into())
+                })?;
+
+                let res: TrackSearchResponse =
+                    spotify_user_api_request(url.as_str(), spotify_access_token)?;
+                Ok(res.tracks.items.into_iter().next().map(|track| track.id))
+            })
+            .collect::<Result<Vec<_>, Error>>()
+    })?;
+
+    let to_cache: Vec<(&str, CachedEntry<&Option<String>>)> = missing_indices
+        .iter()
+        .zip(searched.iter())
+        .map(|(&i, track_id)| (cache_key_refs[i], CachedEntry::new(track_id)))
+        .collect();
+    if !to_cache.is_empty() {
+        crate::cache::set_entries(
+            &**METADATA_CACHE,
+            &CONF.track_searches_cache_hash_name,
+            &to_cache,
+        )?;
+    }
+
+    let mut searched = searched.into_iter();
+    Ok(cached
+        .into_iter()
+        .map(|cached| match cached {
+            Some(track_id) => track_id,
+            None => searched.next().unwrap_or_default(),
+        })
+        .collect())
+}
+
 const MAX_BATCH_ENTITY_COUNT: usize = 50;
 
 fn fetch_batch_entities<T: for<'de> Deserialize<'de>>(
     base_url: &str,
     token: &str,
     spotify_entity_ids: &[&str],
-) -> Result<T, String> {
+) -> Result<T, Error> {
     let url = format!("{}?ids={}", base_url, spotify_entity_ids.join(","));
-    let client = reqwest::blocking::Client::new();
-    client
-        .get(&url)
-        .bearer_auth(token)
-        .send()
-        .map_err(|_err| -> String { "Error requesting batch data from the Spotify API".into() })?
-        .json()
-        .map_err(|err| -> String {
-            error!("Error decoding JSON from Spotify API: {:?}", err);
-            "Error reading data from the Spotify API".into()
-        })
+    let res = send_with_retry(&url, || HTTP_CLIENT.get(&url).bearer_auth(token))?;
+    if !res.status().is_success() {
+        error!(
+            "Got bad status code of {} when fetching batch entities from {}",
+            res.status(),
+            base_url
+        );
+        return Err(Error::spotify_api(
+            Some(res.status().as_u16()),
+            "Error requesting batch data from the Spotify API",
+        ));
+    }
+
+    res.json().map_err(|err| -> Error {
+        error!("Error decoding JSON from Spotify API: {:?}", err);
+        Error::http("Error reading data from the Spotify API", err)
+    })
 }
 
-fn fetch_with_cache<
+/// Fetches the entities with the provided IDs from the Spotify API and stores them in the cache, returning them in the
+/// same order as the IDs
+fn fetch_and_cache_entities<
     ResponseType: for<'de> Deserialize<'de>,
-    T: Clone + Serialize + for<'de> Deserialize<'de>,
+    T: Clone + Serialize + for<'de> Deserialize<'de> + Send,
 >(
+    cache: &dyn MetadataCache,
     cache_key: &str,
     api_url: &str,
     spotify_access_token: &str,
     spotify_ids: &[&str],
-    map_response_to_items: fn(ResponseType) -> Result<Vec<T>, String>,
-) -> Result<Vec<T>, String> {
-    // First, try to get as many items as we can from the cache
-    info!("Checking cache for {} spotify ids...", spotify_ids.len());
-    let cache_res = crate::cache::get_hash_items::<T>(cache_key, spotify_ids)?;
+    map_response_to_items: fn(ResponseType) -> Result<Vec<T>, Error>,
+) -> Result<Vec<T>, Error> {
+    // Fetch all chunks concurrently, keeping them in the same order as the IDs
+    let fetched_chunks: Vec<Vec<T>> = fan_out(|| {
+        spotify_ids
+            .par_chunks(MAX_BATCH_ENTITY_COUNT)
+            .enumerate()
+            .map(|(chunk_ix, chunk)| -> Result<Vec<T>, Error> {
+                info!("Fetching chunk {}...", chunk_ix);
+                let res: ResponseType = fetch_batch_entities(api_url, spotify_access_token, chunk)?;
+                let fetched_artist_data = map_response_to_items(res)?;
 
-    // Fire off a request to Spotify to fill in the missing items
-    let mut missing_indices = Vec::new();
-    let mut missing_ids = Vec::new();
-    for (i, datum) in cache_res.iter().enumerate() {
-        if datum.is_none() {
-            missing_indices.push(i);
-            missing_ids.push(spotify_ids[i]);
+                for i in 0..chunk.len() {
+                    debug_assert_eq!(
+                        chunk[i],
+                        spotify_ids[(chunk_ix * MAX_BATCH_ENTITY_COUNT) + i]
+                    );
+                }
+
+                // Update the cache with the fetched items
+                crate::cache::set_entries(
+                    cache,
+                    cache_key,
+                    &fetched_artist_data
+                        .iter()
+                        .enumerate()
+                        .map(|(i, datum)| (chunk[i], CachedEntry::new(datum)))
+                        .collect::<Vec<_>>(),
+                )?;
+
+                Ok(fetched_artist_data)
+            })
+            .collect::<Result<Vec<_>, Error>>()
+    })?;
+    info!("Fetched all chunks.");
+
+    Ok(fetched_chunks.into_iter().flatten().collect())
+}
+
+lazy_static! {
+    /// `cache_key:spotify_id` of every cached entity that's currently being refreshed in the background
+    static ref PENDING_CACHE_REFRESHES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Removes entities from `PENDING_CACHE_REFRESHES` once their refresh is done, even if it panicked
+struct PendingCacheRefreshGuard {
+    cache_key: String,
+    ids: Vec<String>,
+}
+
+impl PendingCacheRefreshGuard {
+    fn pending_key(cache_key: &str, id: &str) -> String {
+        format!("{}:{}", cache_key, id)
+    }
+}
+
+impl Drop for PendingCacheRefreshGuard {
+    fn drop(&mut self) {
+        let pending = &mut *PENDING_CACHE_REFRESHES
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        for id in &self.ids {
+            pending.remove(&Self::pending_key(&self.cache_key, id));
         }
     }
-    info!(
-        "{}/{} items found in the cache.",
-        cache_res.len() - missing_indices.len(),
-        spotify_ids.len()
-    );
+}
 
-    let mut fetched_entities = Vec::with_capacity(missing_indices.len());
-    for (chunk_ix, chunk) in missing_ids.chunks(MAX_BATCH_ENTITY_COUNT).enumerate() {
-        info!("Fetching chunk {}...", chunk_ix);
-        let res: ResponseType = fetch_batch_entities(api_url, spotify_access_token, chunk)?;
-        let fetched_artist_data = map_response_to_items(res)?;
+/// Re-fetches stale cached entities on the background request pool without waiting for them.  Entities that are
+/// already being refreshed are skipped.  They're fetched with the app's own token rather than the caller's since user
+/// tokens can expire before the refresh gets to run.
+fn refresh_in_background<
+    ResponseType: for<'de> Deserialize<'de> + 'static,
+    T: Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static,
+>(
+    cache: &'static dyn MetadataCache,
+    cache_key: &str,
+    api_url: &str,
+    spotify_ids: &[&str],
+    map_response_to_items: fn(ResponseType) -> Result<Vec<T>, Error>,
+) {
+    let ids: Vec<String> = {
+        let pending = &mut *PENDING_CACHE_REFRESHES.lock().unwrap();
+        spotify_ids
+            .iter()
+            .filter(|&id| pending.insert(PendingCacheRefreshGuard::pending_key(cache_key, id)))
+            .map(|&id| id.to_owned())
+            .collect()
+    };
+    if ids.is_empty() {
+        return;
+    }
 
-        for i in 0..chunk.len() {
-            debug_assert_eq!(
-                chunk[i],
-                missing_ids[(chunk_ix * MAX_BATCH_ENTITY_COUNT) + i]
+    let guard = PendingCacheRefreshGuard {
+        cache_key: cache_key.to_owned(),
+        ids,
+    };
+    let api_url = api_url.to_owned();
+    SPOTIFY_BACKGROUND_POOL.spawn(move || {
+        let PendingCacheRefreshGuard { cache_key, ids } = &guard;
+        info!(
+            "Refreshing {} stale items in the \"{}\" cache...",
+            ids.len(),
+            cache_key
+        );
+        // Don't hold onto the lock while fetching so that other refreshes can run at the same time
+        let spotify_access_token = BACKGROUND_TOKEN_DATA.lock().unwrap().get();
+        let res = spotify_access_token.and_then(|spotify_access_token| {
+            let id_refs: Vec<&str> = ids.iter().map(String::as_str).collect();
+            fetch_and_cache_entities(
+                cache,
+                cache_key,
+                &api_url,
+                &spotify_access_token,
+                &id_refs,
+                map_response_to_items,
+            )
+        });
+        if let Err(err) = res {
+            error!(
+                "Error refreshing stale items in the \"{}\" cache: {}",
+                cache_key, err
             );
         }
+    });
+}
 
-        // Update the cache with the missing items
-        crate::cache::set_hash_items(
-            cache_key,
-            &fetched_artist_data
-                .iter()
-                .enumerate()
-                .map(|(i, datum)| (chunk[i], datum))
-                .collect::<Vec<_>>(),
-        )?;
+/// Returns the entities with the provided IDs, fetching the ones that aren't cached from the Spotify API.  Cached
+/// entities older than `ttl` are returned as-is and refreshed in the background so that responses aren't held up.
+fn fetch_with_cache<
+    ResponseType: for<'de> Deserialize<'de> + 'static,
+    T: Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static,
+>(
+    cache: &'static dyn MetadataCache,
+    cache_key: &str,
+    ttl: chrono::Duration,
+    api_url: &str,
+    spotify_access_token: &str,
+    spotify_ids: &[&str],
+    map_response_to_items: fn(ResponseType) -> Result<Vec<T>, Error>,
+) -> Result<Vec<T>, Error> {
+    // First, try to get as many items as we can from the cache
+    info!("Checking cache for {} spotify ids...", spotify_ids.len());
+    let cache_res = crate::cache::get_entries::<T>(cache, cache_key, spotify_ids)?;
 
-        fetched_entities.extend(fetched_artist_data)
+    let mut missing_ids = Vec::new();
+    let mut stale_ids = Vec::new();
+    for (i, entry) in cache_res.iter().enumerate() {
+        match entry {
+            None => missing_ids.push(spotify_ids[i]),
+            Some(entry) if entry.is_stale(ttl) => stale_ids.push(spotify_ids[i]),
+            Some(_) => (),
+        }
+    }
+    info!(
+        "{}/{} items found in the cache, {} of which are stale.",
+        cache_res.len() - missing_ids.len(),
+        spotify_ids.len(),
+        stale_ids.len()
+    );
+    let fresh_count = cache_res.len() - missing_ids.len() - stale_ids.len();
+    for &(result, count) in &[
+        ("hit", fresh_count),
+        ("stale", stale_ids.len()),
+        ("miss", missing_ids.len()),
+    ] {
+        metrics::METADATA_CACHE_LOOKUPS.inc_by(&[cache_key, result], count as u64);
     }
-    info!("Fetched all chunks.");
+
+    if !stale_ids.is_empty() {
+        refresh_in_background(cache, cache_key, api_url, &stale_ids, map_response_to_items);
+    }
+
+    // Fire off requests to Spotify to fill in the missing items
+    let fetched_entities = fetch_and_cache_entities(
+        cache,
+        cache_key,
+        api_url,
+        spotify_access_token,
+        &missing_ids,
+        map_response_to_items,
+    )?;
 
     let mut i = 0;
     let combined_results = cache_res
         .into_iter()
-        .map(|opt| {
-            opt.unwrap_or_else(|| {
+        .map(|opt| match opt {
+            Some(entry) => entry.value,
+            None => {
                 // We could avoid this clone by reversing the direction in which we fetch the items
                 // but that's 100% premature and likely useless optimization
                 let val = fetched_entities[i].clone();
                 i += 1;
                 val
-            })
+            }
         })
         .collect::<Vec<_>>();
     Ok(combined_results)
@@ -442,25 +1124,38 @@ fn fetch_with_cache<
 pub fn fetch_artists(
     spotify_access_token: &str,
     spotify_ids: &[&str],
-) -> Result<Vec<Artist>, String> {
+) -> Result<Vec<Artist>, Error> {
     fetch_with_cache::<SpotifyBatchArtistsResponse, _>(
+        &**METADATA_CACHE,
         &CONF.artists_cache_hash_name,
-        SPOTIFY_BATCH_ARTISTS_URL,
+        CONF.artists_cache_ttl,
+        &CONF.get_spotify_api_url(SPOTIFY_BATCH_ARTISTS_PATH),
         spotify_access_token,
         spotify_ids,
         |res: SpotifyBatchArtistsResponse| Ok(res.artists),
     )
 }
 
-pub fn fetch_tracks(
+/// Returns the tracks with the provided IDs in the same order, with `None` in place of IDs that don't belong to a track
+pub fn fetch_optional_tracks(
     spotify_access_token: &str,
     spotify_ids: &[&str],
-) -> Result<Vec<Track>, String> {
+) -> Result<Vec<Option<Track>>, Error> {
     fetch_with_cache::<SpotifyBatchTracksResponse, _>(
+        &**METADATA_CACHE,
         &CONF.tracks_cache_hash_name,
-        SPOTIFY_BATCH_TRACKS_URL,
+        CONF.tracks_cache_ttl,
+        &CONF.get_spotify_api_url(SPOTIFY_BATCH_TRACKS_PATH),
         spotify_access_token,
         spotify_ids,
         |res: SpotifyBatchTracksResponse| Ok(res.tracks),
     )
 }
+
+/// Returns the tracks with the provided IDs, skipping IDs that don't belong to a track
+pub fn fetch_tracks(spotify_access_token: &str, spotify_ids: &[&str]) -> Result<Vec<Track>, Error> {
+    Ok(fetch_optional_tracks(spotify_access_token, spotify_ids)?
+        .into_iter()
+        .flatten()
+        .collect())
+}
@@ -1,5 +1,16 @@
+use std::sync::Mutex;
+
 use chrono;
 
+use crate::error::Error;
+
+lazy_static! {
+    /// Token used by background work that runs outside of requests and so can't get at the one in Rocket's managed
+    /// state.  It's fetched the first time that it's needed.
+    pub static ref BACKGROUND_TOKEN_DATA: Mutex<SpotifyTokenData> =
+        Mutex::new(SpotifyTokenData::expired());
+}
+
 pub struct SpotifyTokenData {
     pub token: String,
     pub expiry: chrono::DateTime<chrono::Local>,
@@ -8,21 +19,28 @@ pub struct SpotifyTokenData {
 impl SpotifyTokenData {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        let mut s = SpotifyTokenData {
-            token: "".into(),
-            expiry: chrono::Local::now(),
-        };
+        let mut s = Self::expired();
         s.refresh()
             .expect("Failed to fetch initial spotify token for Rocket managed state");
         s
     }
 
-    pub fn refresh(&mut self) -> Result<(), String> {
+    /// Token data that will be refreshed the first time that it's used
+    fn expired() -> Self {
+        SpotifyTokenData {
+            token: "".into(),
+            expiry: chrono::Local::now(),
+        }
+    }
+
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        let res = crate::spotify_api::fetch_auth_token();
+        crate::metrics::record_token_refresh("app", &res);
         let crate::models::AccessTokenResponse {
             access_token,
             expires_in,
             ..
-        } = crate::spotify_api::fetch_auth_token()?;
+        } = res?;
         self.token = access_token;
         info!(
             "Got new Spotify access token; expires in: {} seconds",
@@ -33,7 +51,7 @@ impl SpotifyTokenData {
         Ok(())
     }
 
-    pub fn get(&mut self) -> Result<String, String> {
+    pub fn get(&mut self) -> Result<String, Error> {
         let now = chrono::Local::now();
         if now > self.expiry {
             info!(
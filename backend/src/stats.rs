@@ -3,7 +3,7 @@ use std::cmp::Reverse;
 use chrono::NaiveDateTime;
 use hashbrown::{HashMap, HashSet};
 
-use crate::models::{Artist, TimeFrames};
+use crate::models::{Artist, TimeFrames, Track};
 
 /// This is a pretty arbitrary algorithm with the goal of assigning a score to an item based on how many total items
 /// there are and the item's rank in the collection.  It is used to construct the genres treemap on the frontend.
@@ -12,6 +12,29 @@ fn weight_data_point(total_items: usize, ranking: usize) -> usize {
         .powf(2.7 * ((total_items - ranking) as f32 / total_items as f32))) as usize
 }
 
+/// Adds the genres of each of the provided artists to `genre_counts`.  Artists must be provided in ranked order; if
+/// `weight` is set, genres of higher-ranked artists count for more.
+fn add_genre_counts(
+    genre_counts: &mut HashMap<String, usize>,
+    ranked_artists: &[&Artist],
+    weight: bool,
+) {
+    let artist_count = ranked_artists.len();
+
+    for (i, artist) in ranked_artists.iter().enumerate() {
+        if let Some(genres) = &artist.genres {
+            for genre in genres {
+                let count = genre_counts.entry(genre.clone()).or_insert(0);
+                *count += if weight {
+                    weight_data_point(artist_count, i)
+                } else {
+                    1
+                };
+            }
+        }
+    }
+}
+
 /// Give an array of top artists, extrapolates the most listened-to genres for each update.
 pub fn get_top_genres_by_artists(
     artists_by_id: &HashMap<String, Artist>,
@@ -27,26 +50,18 @@ pub fn get_top_genres_by_artists(
         let mut genre_counts = HashMap::new();
 
         for (_tf, artist_ids) in update.iter() {
-            let artist_count = artist_ids.len();
-
-            for (i, artist_id) in artist_ids.into_iter().enumerate() {
-                let artist = artists_by_id
-                    .get(&*artist_id)
-                    .expect(&format!("Artist with id {} not found in corpus", artist_id));
-                if let Some(genres) = &artist.genres {
-                    for genre in genres {
-                        all_genres.insert(genre.clone());
-                        let count = genre_counts.entry(genre.clone()).or_insert(0);
-                        *count += if weight {
-                            weight_data_point(artist_count, i)
-                        } else {
-                            1
-                        };
-                    }
-                }
-            }
+            let artists: Vec<&Artist> = artist_ids
+                .iter()
+                .map(|artist_id| {
+                    artists_by_id
+                        .get(&*artist_id)
+                        .expect(&format!("Artist with id {} not found in corpus", artist_id))
+                })
+                .collect();
+            add_genre_counts(&mut genre_counts, &artists, weight);
         }
 
+        all_genres.extend(genre_counts.keys().cloned());
         all_genre_counts.push(genre_counts);
     }
 
@@ -88,6 +103,96 @@ pub fn compute_track_popularity_scores(
     top_tracks
 }
 
+#[derive(Serialize, Debug)]
+pub struct AlbumScores {
+    /// `(album_id, score)` for the latest update for each timeframe, ordered from highest to lowest score
+    pub current: TimeFrames<(String, usize)>,
+    /// `(album_id, score)` summed over all updates and timeframes, ordered from highest to lowest score
+    pub all_time: Vec<(String, usize)>,
+    /// IDs of the tracks that contributed to each album's score, ordered from most to least popular
+    pub tracks_by_album_id: HashMap<String, Vec<String>>,
+    pub timestamps: Vec<NaiveDateTime>,
+    /// The album's ranking for the short, medium, and long timeframes for each update in `timestamps`
+    pub rank_history_by_album_id: HashMap<String, Vec<[Option<u16>; 3]>>,
+}
+
+fn sort_by_score(scores: HashMap<String, usize>) -> Vec<(String, usize)> {
+    let mut scores: Vec<_> = scores.into_iter().collect();
+    scores.sort_by(|(id_a, score_a), (id_b, score_b)| {
+        score_b.cmp(score_a).then_with(|| id_a.cmp(id_b))
+    });
+    scores
+}
+
+/// Scores the albums of a ranked list of tracks using the same scheme as `compute_track_popularity_scores`; each
+/// track adds to its album's score based on its ranking.
+fn score_albums(
+    tracks_by_id: &HashMap<String, Track>,
+    track_ids: &[String],
+) -> Vec<(String, usize)> {
+    let track_count = track_ids.len();
+    let mut album_scores: HashMap<String, usize> = HashMap::new();
+
+    for (i, track_id) in track_ids.iter().enumerate() {
+        let track = match tracks_by_id.get(track_id) {
+            Some(track) => track,
+            None => continue,
+        };
+        *album_scores.entry(track.album.id.clone()).or_insert(0) += track_count - i;
+    }
+
+    sort_by_score(album_scores)
+}
+
+/// Aggregates a user's top tracks history by album, producing current and all-time album scores along with each
+/// album's ranking in every update.
+pub fn compute_album_scores(
+    tracks_by_id: &HashMap<String, Track>,
+    updates: &[(NaiveDateTime, TimeFrames<String>)],
+) -> AlbumScores {
+    let timestamps: Vec<NaiveDateTime> = updates.iter().map(|(ts, _)| *ts).collect();
+    let mut current = TimeFrames::default();
+    let mut all_time_scores: HashMap<String, usize> = HashMap::new();
+    let mut rank_history_by_album_id: HashMap<String, Vec<[Option<u16>; 3]>> = HashMap::new();
+
+    for (i, (_ts, timeframes)) in updates.iter().enumerate() {
+        for (timeframe_id, (timeframe, track_ids)) in timeframes.iter().enumerate() {
+            let album_scores = score_albums(tracks_by_id, track_ids);
+
+            for (ranking, (album_id, score)) in album_scores.iter().enumerate() {
+                *all_time_scores.entry(album_id.clone()).or_insert(0) += score;
+                rank_history_by_album_id
+                    .entry(album_id.clone())
+                    .or_insert_with(|| vec![[None; 3]; timestamps.len()])[i][timeframe_id] =
+                    Some(ranking as u16);
+            }
+
+            if i == updates.len() - 1 {
+                current.set(timeframe, album_scores);
+            }
+        }
+    }
+
+    // Tracks are ordered by their own popularity so that the top contributors of each album come first
+    let mut tracks_by_album_id: HashMap<String, Vec<String>> = HashMap::new();
+    for (track_id, _score) in compute_track_popularity_scores(updates) {
+        if let Some(track) = tracks_by_id.get(&track_id) {
+            tracks_by_album_id
+                .entry(track.album.id.clone())
+                .or_insert_with(Vec::new)
+                .push(track_id);
+        }
+    }
+
+    AlbumScores {
+        current,
+        all_time: sort_by_score(all_time_scores),
+        tracks_by_album_id,
+        timestamps,
+        rank_history_by_album_id,
+    }
+}
+
 pub fn compute_genre_ranking_history(
     updates: Vec<(NaiveDateTime, TimeFrames<crate::db_util::ArtistRanking>)>,
 ) -> (
@@ -135,3 +240,378 @@ pub fn compute_genre_ranking_history(
         popularity_history,
     )
 }
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RankedItem {
+    pub id: String,
+    pub ranking: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RankChange {
+    pub id: String,
+    pub from_ranking: usize,
+    pub to_ranking: usize,
+    /// Number of places that the item moved up; negative if it moved down
+    pub delta: i64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RankingsDiff {
+    /// Items that weren't ranked before, with their new ranking
+    pub entered: Vec<RankedItem>,
+    /// Items that are no longer ranked, with their old ranking
+    pub dropped: Vec<RankedItem>,
+    /// Items whose ranking changed, ordered by their new ranking
+    pub moved: Vec<RankChange>,
+}
+
+/// Computes which items entered, dropped out of, or moved within a ranked list of item IDs between two updates.
+pub fn diff_rankings(from: &[String], to: &[String]) -> RankingsDiff {
+    let from_rankings: HashMap<&str, usize> = from
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (id.as_str(), i))
+        .collect();
+    let to_ids: HashSet<&str> = to.iter().map(String::as_str).collect();
+
+    let mut diff = RankingsDiff::default();
+    for (to_ranking, id) in to.iter().enumerate() {
+        match from_rankings.get(id.as_str()) {
+            None => diff.entered.push(RankedItem {
+                id: id.clone(),
+                ranking: to_ranking,
+            }),
+            Some(&from_ranking) if from_ranking != to_ranking => diff.moved.push(RankChange {
+                id: id.clone(),
+                from_ranking,
+                to_ranking,
+                delta: from_ranking as i64 - to_ranking as i64,
+            }),
+            Some(_) => (),
+        }
+    }
+    diff.dropped = from
+        .iter()
+        .enumerate()
+        .filter(|(_, id)| !to_ids.contains(id.as_str()))
+        .map(|(ranking, id)| RankedItem {
+            id: id.clone(),
+            ranking,
+        })
+        .collect();
+
+    diff
+}
+
+/// Diffs each timeframe of two updates, returning the diffs keyed by timeframe
+pub fn diff_timeframes(
+    from: &TimeFrames<String>,
+    to: &TimeFrames<String>,
+) -> HashMap<String, RankingsDiff> {
+    from.iter()
+        .zip(to.iter())
+        .map(|((timeframe, from_ids), (_, to_ids))| {
+            (timeframe.to_string(), diff_rankings(from_ids, to_ids))
+        })
+        .collect()
+}
+
+#[derive(Serialize, Debug)]
+pub struct SharedItem {
+    pub id: String,
+    pub ranking_a: usize,
+    pub ranking_b: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ItemsComparison {
+    /// Items that both users have, ordered by their combined ranking
+    pub shared: Vec<SharedItem>,
+    pub unique_a: Vec<String>,
+    pub unique_b: Vec<String>,
+    /// Weighted Jaccard similarity of the two sets of items, from 0 (nothing in common) to 1 (identical)
+    pub similarity: f32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TimeframeComparison {
+    pub artists: ItemsComparison,
+    pub tracks: ItemsComparison,
+    pub genres: ItemsComparison,
+    /// Average of the artist, track, and genre similarities
+    pub similarity: f32,
+}
+
+/// Computes the weighted Jaccard similarity (sum of the minimum weights over sum of the maximum weights) of two sets of
+/// weighted items.
+fn weighted_jaccard_similarity(
+    weights_a: &HashMap<String, usize>,
+    weights_b: &HashMap<String, usize>,
+) -> f32 {
+    let (mut intersection, mut union) = (0, 0);
+    for (item, &weight_a) in weights_a {
+        let weight_b = weights_b.get(item).copied().unwrap_or(0);
+        intersection += weight_a.min(weight_b);
+        union += weight_a.max(weight_b);
+    }
+    for (item, &weight_b) in weights_b {
+        if !weights_a.contains_key(item) {
+            union += weight_b;
+        }
+    }
+
+    if union == 0 {
+        return 0.0;
+    }
+    intersection as f32 / union as f32
+}
+
+fn weight_ranked_items(ranked_items: &[String]) -> HashMap<String, usize> {
+    ranked_items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| (item.clone(), weight_data_point(ranked_items.len(), i)))
+        .collect()
+}
+
+/// Compares two ranked lists of items, using the provided weights to compute their similarity.
+fn compare_weighted_items(
+    ranked_a: &[String],
+    weights_a: &HashMap<String, usize>,
+    ranked_b: &[String],
+    weights_b: &HashMap<String, usize>,
+) -> ItemsComparison {
+    let rankings_b: HashMap<&str, usize> = ranked_b
+        .iter()
+        .enumerate()
+        .map(|(i, item)| (item.as_str(), i))
+        .collect();
+
+    let mut shared = Vec::new();
+    let mut unique_a = Vec::new();
+    for (ranking_a, item) in ranked_a.iter().enumerate() {
+        match rankings_b.get(item.as_str()) {
+            Some(&ranking_b) => shared.push(SharedItem {
+                id: item.clone(),
+                ranking_a,
+                ranking_b,
+            }),
+            None => unique_a.push(item.clone()),
+        }
+    }
+    shared.sort_by_key(|item| item.ranking_a + item.ranking_b);
+
+    let shared_ids: HashSet<&str> = shared.iter().map(|item| item.id.as_str()).collect();
+    let unique_b = ranked_b
+        .iter()
+        .filter(|item| !shared_ids.contains(item.as_str()))
+        .cloned()
+        .collect();
+
+    ItemsComparison {
+        shared,
+        unique_a,
+        unique_b,
+        similarity: weighted_jaccard_similarity(weights_a, weights_b),
+    }
+}
+
+/// Compares two ranked lists of item IDs, weighting items by their ranking.
+pub fn compare_ranked_items(ranked_a: &[String], ranked_b: &[String]) -> ItemsComparison {
+    compare_weighted_items(
+        ranked_a,
+        &weight_ranked_items(ranked_a),
+        ranked_b,
+        &weight_ranked_items(ranked_b),
+    )
+}
+
+/// Returns the genres of the provided ranked artists along with their weights, ordered from highest to lowest weight.
+fn get_ranked_genres(ranked_artists: &[Artist]) -> (Vec<String>, HashMap<String, usize>) {
+    let mut genre_counts = HashMap::new();
+    let artists: Vec<&Artist> = ranked_artists.iter().collect();
+    add_genre_counts(&mut genre_counts, &artists, true);
+
+    let mut ranked_genres: Vec<String> = genre_counts.keys().cloned().collect();
+    ranked_genres.sort_by(|genre_a, genre_b| {
+        genre_counts[genre_b]
+            .cmp(&genre_counts[genre_a])
+            .then_with(|| genre_a.cmp(genre_b))
+    });
+    (ranked_genres, genre_counts)
+}
+
+/// Compares the top artists, tracks, and genres of two users for a single timeframe.
+pub fn compare_timeframe(
+    artists_a: &[Artist],
+    tracks_a: &[Track],
+    artists_b: &[Artist],
+    tracks_b: &[Track],
+) -> TimeframeComparison {
+    let get_artist_ids = |artists: &[Artist]| -> Vec<String> {
+        artists.iter().map(|artist| artist.id.clone()).collect()
+    };
+    let get_track_ids =
+        |tracks: &[Track]| -> Vec<String> { tracks.iter().map(|track| track.id.clone()).collect() };
+
+    let artists = compare_ranked_items(&get_artist_ids(artists_a), &get_artist_ids(artists_b));
+    let tracks = compare_ranked_items(&get_track_ids(tracks_a), &get_track_ids(tracks_b));
+    let (ranked_genres_a, genre_weights_a) = get_ranked_genres(artists_a);
+    let (ranked_genres_b, genre_weights_b) = get_ranked_genres(artists_b);
+    let genres = compare_weighted_items(
+        &ranked_genres_a,
+        &genre_weights_a,
+        &ranked_genres_b,
+        &genre_weights_b,
+    );
+
+    let similarity = (artists.similarity + tracks.similarity + genres.similarity) / 3.0;
+    TimeframeComparison {
+        artists,
+        tracks,
+        genres,
+        similarity,
+    }
+}
+
+#[cfg(test)]
+fn ids(items: &[&str]) -> Vec<String> {
+    items.iter().map(|&item| item.into()).collect()
+}
+
+#[test]
+fn ranked_items_comparison() {
+    let identical = compare_ranked_items(&ids(&["a", "b", "c"]), &ids(&["a", "b", "c"]));
+    assert_eq!(identical.similarity, 1.0);
+    assert!(identical.unique_a.is_empty() && identical.unique_b.is_empty());
+
+    let disjoint = compare_ranked_items(&ids(&["a", "b"]), &ids(&["c", "d"]));
+    assert_eq!(disjoint.similarity, 0.0);
+    assert!(disjoint.shared.is_empty());
+
+    let partial = compare_ranked_items(&ids(&["a", "b", "c"]), &ids(&["c", "d", "a"]));
+    let shared: Vec<(&str, usize, usize)> = partial
+        .shared
+        .iter()
+        .map(|item| (item.id.as_str(), item.ranking_a, item.ranking_b))
+        .collect();
+    assert_eq!(shared, vec![("a", 0, 2), ("c", 2, 0)]);
+    assert_eq!(partial.unique_a, ids(&["b"]));
+    assert_eq!(partial.unique_b, ids(&["d"]));
+    assert!(partial.similarity > 0.0 && partial.similarity < 1.0);
+}
+
+#[test]
+fn rankings_diff() {
+    let diff = diff_rankings(&ids(&["a", "b", "c", "d"]), &ids(&["b", "a", "e", "d"]));
+    assert_eq!(
+        diff.entered,
+        vec![RankedItem {
+            id: "e".into(),
+            ranking: 2
+        }]
+    );
+    assert_eq!(
+        diff.dropped,
+        vec![RankedItem {
+            id: "c".into(),
+            ranking: 2
+        }]
+    );
+    assert_eq!(
+        diff.moved,
+        vec![
+            RankChange {
+                id: "b".into(),
+                from_ranking: 1,
+                to_ranking: 0,
+                delta: 1
+            },
+            RankChange {
+                id: "a".into(),
+                from_ranking: 0,
+                to_ranking: 1,
+                delta: -1
+            },
+        ]
+    );
+}
+
+#[test]
+fn album_scores() {
+    let scores = |items: &[(&str, usize)]| -> Vec<(String, usize)> {
+        items
+            .iter()
+            .map(|&(id, score)| (id.into(), score))
+            .collect()
+    };
+
+    let track = |id: &str, album_id: &str| {
+        let artist = crate::mock_spotify::mock_artist("artist", "Artist", &[]);
+        let mut track = crate::mock_spotify::mock_track(id, id, artist);
+        track.album.id = album_id.into();
+        track
+    };
+    let tracks_by_id: HashMap<String, Track> = vec![
+        track("t1", "album-a"),
+        track("t2", "album-a"),
+        track("t3", "album-b"),
+        track("t4", "album-c"),
+    ]
+    .into_iter()
+    .map(|track| (track.id.clone(), track))
+    .collect();
+
+    let update = |hour: u32, short: &[&str], medium: &[&str]| {
+        (
+            chrono::NaiveDate::from_ymd(2020, 7, 1).and_hms(hour, 0, 0),
+            TimeFrames {
+                short: ids(short),
+                medium: ids(medium),
+                long: Vec::new(),
+            },
+        )
+    };
+    let updates = vec![
+        // Both tracks from album A contribute to its score in the same timeframe
+        update(0, &["t1", "t3", "t2"], &["t4"]),
+        // Albums A and B tie with a score of 3 in the short timeframe; ties are broken by album ID
+        update(1, &["t3", "t1", "t2"], &["t4", "t3"]),
+    ];
+
+    let album_scores = compute_album_scores(&tracks_by_id, &updates);
+
+    assert_eq!(
+        album_scores.current.short,
+        scores(&[("album-a", 3), ("album-b", 3)])
+    );
+    assert_eq!(
+        album_scores.current.medium,
+        scores(&[("album-c", 2), ("album-b", 1)])
+    );
+    assert!(album_scores.current.long.is_empty());
+    // Scores are summed across every update and timeframe
+    assert_eq!(
+        album_scores.all_time,
+        scores(&[("album-a", 7), ("album-b", 6), ("album-c", 3)])
+    );
+    assert_eq!(
+        album_scores.tracks_by_album_id["album-a"],
+        ids(&["t1", "t2"])
+    );
+    assert_eq!(album_scores.tracks_by_album_id["album-b"], ids(&["t3"]));
+    assert_eq!(album_scores.timestamps.len(), 2);
+    assert_eq!(
+        album_scores.rank_history_by_album_id["album-a"],
+        vec![[Some(0), None, None], [Some(0), None, None]]
+    );
+    assert_eq!(
+        album_scores.rank_history_by_album_id["album-b"],
+        vec![[Some(1), None, None], [Some(1), Some(1), None]]
+    );
+    assert_eq!(
+        album_scores.rank_history_by_album_id["album-c"],
+        vec![[None, Some(0), None], [None, Some(0), None]]
+    );
+}